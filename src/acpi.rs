@@ -0,0 +1,121 @@
+//! Just enough ACPI to find the processors: locates the RSDP, walks the
+//! RSDT/XSDT and reads the local APIC entries of the MADT.
+
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+const LOCAL_APIC_ENABLED: u32 = 1;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub processors: Vec<Processor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadChecksum,
+    NoMadt,
+}
+
+struct PhysMemory {
+    offset: VirtAddr,
+}
+
+impl PhysMemory {
+    fn read<T: Copy>(&self, addr: u64) -> T {
+        let virt = self.offset + addr;
+        unsafe { ptr::read_unaligned(virt.as_ptr::<T>()) }
+    }
+
+    fn checksum_ok(&self, addr: u64, len: u64) -> bool {
+        (0..len).fold(0u8, |sum, i| sum.wrapping_add(self.read::<u8>(addr + i))) == 0
+    }
+}
+
+fn find_rsdp(mem: &PhysMemory) -> Option<u64> {
+    // The first KiB of the extended BIOS data area, then the BIOS ROM.
+    let ebda = u64::from(mem.read::<u16>(0x40e)) << 4;
+    (ebda..ebda + 1024)
+        .step_by(16)
+        .chain((0xe0000..0x100000).step_by(16))
+        .find(|&addr| mem.read::<[u8; 8]>(addr) == *b"RSD PTR " && mem.checksum_ok(addr, 20))
+}
+
+/// Finds the MADT and returns the local APIC address together with all
+/// usable processors.
+pub fn read_madt(physical_memory_offset: VirtAddr) -> Result<Madt, AcpiError> {
+    let mem = PhysMemory {
+        offset: physical_memory_offset,
+    };
+    let rsdp = find_rsdp(&mem).ok_or(AcpiError::NoRsdp)?;
+    let revision = mem.read::<u8>(rsdp + 15);
+
+    let (root, entry_size) = if revision >= 2 {
+        (mem.read::<u64>(rsdp + 24), 8)
+    } else {
+        (u64::from(mem.read::<u32>(rsdp + 16)), 4)
+    };
+    let root_len = u64::from(mem.read::<u32>(root + 4));
+    // Shorter than its header means truncated or zeroed.
+    if root_len < 36 || !mem.checksum_ok(root, root_len) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    let madt = (0..(root_len - 36) / entry_size)
+        .map(|i| {
+            let entry = root + 36 + i * entry_size;
+            if entry_size == 8 {
+                mem.read::<u64>(entry)
+            } else {
+                u64::from(mem.read::<u32>(entry))
+            }
+        })
+        .find(|&table| mem.read::<[u8; 4]>(table) == *b"APIC")
+        .ok_or(AcpiError::NoMadt)?;
+    let madt_len = u64::from(mem.read::<u32>(madt + 4));
+    if madt_len < 44 || !mem.checksum_ok(madt, madt_len) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    let mut local_apic_address = u64::from(mem.read::<u32>(madt + 36));
+    let mut processors = Vec::new();
+    let mut entry = madt + 44;
+    while entry + 2 <= madt + madt_len {
+        let kind = mem.read::<u8>(entry);
+        let len = u64::from(mem.read::<u8>(entry + 1));
+        if len < 2 {
+            break;
+        }
+        match kind {
+            MADT_LOCAL_APIC => {
+                let flags = mem.read::<u32>(entry + 4);
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    processors.push(Processor {
+                        acpi_id: mem.read::<u8>(entry + 2),
+                        apic_id: mem.read::<u8>(entry + 3),
+                    });
+                }
+            }
+            MADT_LOCAL_APIC_OVERRIDE => local_apic_address = mem.read::<u64>(entry + 4),
+            _ => {}
+        }
+        entry += len;
+    }
+
+    Ok(Madt {
+        local_apic_address: PhysAddr::new(local_apic_address),
+        processors,
+    })
+}
@@ -0,0 +1,349 @@
+//! Address spaces of their own for user programs.
+//!
+//! Each address space has its own level 4 table. The entries covering user
+//! space belong to it alone; all others are copied from the kernel's table
+//! when it is created, so the kernel half is shared. Kernel mappings made
+//! later below a level 4 entry that was empty at that point are not seen.
+//!
+//! `fork` shares the user frames between both address spaces instead of
+//! copying them. Writable pages become read-only in both and are marked
+//! with `COPY_ON_WRITE`; the first write to one faults and `handle_write_fault`
+//! gives the writer a copy of its own. Frames mapped more than once are
+//! reference counted, so they are only freed with their last mapping.
+//!
+//! Each address space also has its own user heap, handed out by
+//! `syscall::ALLOC` from `USER_HEAP_START` on.
+
+use crate::memory;
+use crate::user::{self, USER_SPACE_END, USER_SPACE_START};
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page_table::{PageTableEntry, PageTableLevel};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Marks a page shared by `fork` that was writable before.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Where the user heap of each address space starts.
+pub const USER_HEAP_START: u64 = 0x0000_1800_0000_0000;
+
+const USER_LEVEL_4_ENTRIES: Range<usize> =
+    (USER_SPACE_START >> 39) as usize..(USER_SPACE_END >> 39) as usize;
+
+// The number of additional mappings of each frame mapped more than once.
+static SHARED_FRAMES: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+// The end of the user heap of each address space that has one, by the frame
+// of its level 4 table.
+static HEAP_ENDS: Mutex<BTreeMap<PhysFrame, u64>> = Mutex::new(BTreeMap::new());
+
+/// Takes `len` bytes from the user heap of the active address space and
+/// returns where they start, or `None` if they do not fit in user space.
+pub fn reserve_heap(len: u64) -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        let mut ends = HEAP_ENDS.lock();
+        let end = ends.entry(Cr3::read().0).or_insert(USER_HEAP_START);
+        let start = *end;
+        *end = start
+            .checked_add(len)
+            .filter(|&end| end <= USER_SPACE_END)?;
+        Some(start)
+    })
+}
+
+/// Gives the `len` bytes from `start` back to the user heap of the active
+/// address space, unless more was taken from it since.
+pub fn unreserve_heap(start: u64, len: u64) {
+    interrupts::without_interrupts(|| {
+        if let Some(end) = HEAP_ENDS.lock().get_mut(&Cr3::read().0) {
+            if *end == start + len {
+                *end = start;
+            }
+        }
+    });
+}
+
+fn share(frame: PhysFrame) {
+    interrupts::without_interrupts(|| *SHARED_FRAMES.lock().entry(frame).or_insert(0) += 1);
+}
+
+// Drops one mapping of `frame` and returns whether others remain.
+fn unshare(frame: PhysFrame) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut shared = SHARED_FRAMES.lock();
+        match shared.get_mut(&frame) {
+            Some(1) => {
+                shared.remove(&frame);
+                true
+            }
+            Some(count) => {
+                *count -= 1;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// How many address spaces map `frame`, as far as `fork` is concerned.
+pub fn mapping_count(frame: PhysFrame) -> usize {
+    interrupts::without_interrupts(|| SHARED_FRAMES.lock().get(&frame).map_or(1, |n| n + 1))
+}
+
+fn table(frame: PhysFrame) -> *mut PageTable {
+    (memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// An address space without user mappings, sharing the kernel's.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<Self> {
+        let level_4_frame = frame_allocator.allocate_frame()?;
+        let kernel = unsafe { &*table(memory::kernel_level_4_frame()) };
+        let level_4_table = unsafe { &mut *table(level_4_frame) };
+        for (i, entry) in level_4_table.iter_mut().enumerate() {
+            if USER_LEVEL_4_ENTRIES.contains(&i) {
+                entry.set_unused();
+            } else {
+                *entry = kernel[i].clone();
+            }
+        }
+        Some(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// A mapper for this address space, which need not be the active one.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                &mut *table(self.level_4_frame),
+                memory::physical_memory_offset(),
+            )
+        }
+    }
+
+    /// See `user::map_user_pages`.
+    pub fn map_user_pages(
+        &mut self,
+        start: Page,
+        count: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<(), MapToError<Size4KiB>> {
+        user::map_user_pages(start, count, flags, &mut self.mapper(), frame_allocator)
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Makes this the address space of the current CPU.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may still refer to user mappings of the previous address space.
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /// A copy of this address space sharing all user frames with it, which
+    /// are copied on the first write from either side.
+    pub fn fork(
+        &mut self,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Option<AddressSpace> {
+        let child = AddressSpace::new(frame_allocator)?;
+        let parent_table = unsafe { &mut *table(self.level_4_frame) };
+        let child_table = unsafe { &mut *table(child.level_4_frame) };
+        for i in USER_LEVEL_4_ENTRIES {
+            let entry = &mut parent_table[i];
+            if let Ok(frame) = entry.frame() {
+                match unsafe { fork_table(frame, PageTableLevel::Three, frame_allocator) } {
+                    Some(copy) => child_table[i].set_frame(copy, entry.flags()),
+                    None => {
+                        child.free(frame_allocator);
+                        return None;
+                    }
+                }
+            }
+        }
+        interrupts::without_interrupts(|| {
+            let mut ends = HEAP_ENDS.lock();
+            if let Some(&end) = ends.get(&self.level_4_frame) {
+                ends.insert(child.level_4_frame, end);
+            }
+        });
+        // The parent lost write access to its pages.
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        Some(child)
+    }
+
+    /// Frees the user frames no other address space maps, the page tables
+    /// and the level 4 table. Switches back to the kernel's table first if
+    /// this one is active.
+    pub fn free(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        let level_4_frame = self.level_4_frame;
+        core::mem::forget(self);
+        unsafe { free_frames(level_4_frame, frame_deallocator) };
+    }
+}
+
+/// Frees all frames through the frame allocator handed to
+/// `memory::set_global`, so an address space must not be dropped inside
+/// `memory::with_global`. Without a global allocator the frames are leaked.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let level_4_frame = self.level_4_frame;
+        memory::with_global(|_, frame_allocator| unsafe {
+            free_frames(level_4_frame, frame_allocator)
+        });
+    }
+}
+
+unsafe fn free_frames(
+    level_4_frame: PhysFrame,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if Cr3::read().0 == level_4_frame {
+        Cr3::write(memory::kernel_level_4_frame(), Cr3Flags::empty());
+    }
+    interrupts::without_interrupts(|| HEAP_ENDS.lock().remove(&level_4_frame));
+    let level_4_table = &*table(level_4_frame);
+    for i in USER_LEVEL_4_ENTRIES {
+        if let Ok(frame) = level_4_table[i].frame() {
+            free_table(frame, PageTableLevel::Three, frame_deallocator);
+        }
+    }
+    frame_deallocator.deallocate_frame(level_4_frame);
+}
+
+// Frees the table in `frame` together with everything mapped through it.
+unsafe fn free_table(
+    frame: PhysFrame,
+    level: PageTableLevel,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    for entry in (*table(frame)).iter() {
+        if let Ok(child) = entry.frame() {
+            match level.next_lower_level() {
+                Some(lower) => free_table(child, lower, frame_deallocator),
+                None if unshare(child) => {}
+                None => frame_deallocator.deallocate_frame(child),
+            }
+        }
+    }
+    frame_deallocator.deallocate_frame(frame);
+}
+
+// Copies the table in `frame` and the tables below it, sharing the frames
+// they map. If a frame cannot be allocated, the copy made so far is freed.
+unsafe fn fork_table(
+    frame: PhysFrame,
+    level: PageTableLevel,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Option<PhysFrame> {
+    let copy = frame_allocator.allocate_frame()?;
+    let copy_table = &mut *table(copy);
+    copy_table.zero();
+    for (i, entry) in (*table(frame)).iter_mut().enumerate() {
+        let child = match entry.frame() {
+            Ok(child) => child,
+            Err(_) => continue,
+        };
+        match level.next_lower_level() {
+            Some(lower) => match fork_table(child, lower, frame_allocator) {
+                Some(child_copy) => copy_table[i].set_frame(child_copy, entry.flags()),
+                None => {
+                    free_table(copy, level, frame_allocator);
+                    return None;
+                }
+            },
+            None => {
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COPY_ON_WRITE);
+                    entry.set_flags(flags);
+                }
+                copy_table[i].set_frame(child, flags);
+                share(child);
+            }
+        }
+    }
+    Some(copy)
+}
+
+// The entry mapping `address` in the active level 4 table, if it is mapped
+// by a 4 KiB page.
+unsafe fn active_leaf_entry(address: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let indexes = [address.p4_index(), address.p3_index(), address.p2_index()];
+    let mut frame = Cr3::read().0;
+    for index in indexes.iter() {
+        frame = (&*table(frame))[*index].frame().ok()?;
+    }
+    let entry = &mut (&mut *table(frame))[address.p1_index()];
+    entry.frame().ok()?;
+    Some(entry)
+}
+
+/// Resolves a write to a copy-on-write page, giving the active address space
+/// a copy of the frame unless no other one maps it anymore. Called by the
+/// page fault handler; returns whether the fault was handled.
+///
+/// # Panics
+///
+/// If the fault happened inside `memory::with_global`, whose lock the copy
+/// needs.
+pub fn handle_write_fault(address: VirtAddr) -> bool {
+    let entry = match unsafe { active_leaf_entry(address) } {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false,
+    };
+    assert!(
+        !memory::in_with_global(),
+        "copy-on-write fault at {:?} inside memory::with_global",
+        address
+    );
+    let frame = entry.frame().unwrap();
+    let flags = (entry.flags() | PageTableFlags::WRITABLE) - COPY_ON_WRITE;
+
+    if unshare(frame) {
+        let copy = match memory::with_global(|_, frame_allocator| frame_allocator.allocate_frame())
+        {
+            Some(Some(copy)) => copy,
+            _ => {
+                share(frame);
+                return false;
+            }
+        };
+        let offset = memory::physical_memory_offset();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+                (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+                4096,
+            );
+        }
+        entry.set_frame(copy, flags);
+    } else {
+        entry.set_flags(flags);
+    }
+    tlb::flush(address);
+    true
+}
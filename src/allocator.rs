@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use fixed::FixedSizeBlockAllocator;
 use list::LinkedListAllocator;
 // use linked_list_allocator::LockedHeap;
@@ -42,6 +43,14 @@ unsafe impl GlobalAlloc for ExampleAllocator {
 static ALLOCATOR: MutexWrapper<FixedSizeBlockAllocator> =
     MutexWrapper::new(FixedSizeBlockAllocator::new());
 
+// Bytes handed out by the global allocator and not freed yet.
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// How many bytes of the heap are allocated.
+pub fn heap_used() -> usize {
+    HEAP_USED.load(Ordering::Relaxed)
+}
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
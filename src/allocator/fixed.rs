@@ -1,6 +1,7 @@
 use alloc::alloc::Layout;
 use core::ptr;
 use core::{mem, ptr::NonNull};
+use x86_64::instructions::interrupts;
 
 struct FixedNode {
     next: Option<&'static mut FixedNode>,
@@ -41,46 +42,58 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-use super::MutexWrapper;
+use super::{MutexWrapper, HEAP_USED};
 use alloc::alloc::GlobalAlloc;
+use core::sync::atomic::Ordering;
 
 unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut FixedNode as *mut u8
-                }
-                None => {
-                    let size = BLOCK_SIZES[index];
-                    let align = size;
-                    let layout = Layout::from_size_align(size, align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
-            None => allocator.fallback_alloc(layout),
+        // Threads are preempted by the timer, so the lock must not be held
+        // with interrupts enabled.
+        let ptr = interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            match list_index(&layout) {
+                Some(index) => match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        allocator.list_heads[index] = node.next.take();
+                        node as *mut FixedNode as *mut u8
+                    }
+                    None => {
+                        let size = BLOCK_SIZES[index];
+                        let align = size;
+                        let layout = Layout::from_size_align(size, align).unwrap();
+                        allocator.fallback_alloc(layout)
+                    }
+                },
+                None => allocator.fallback_alloc(layout),
+            }
+        });
+        if !ptr.is_null() {
+            HEAP_USED.fetch_add(layout.size(), Ordering::Relaxed);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = FixedNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                assert!(mem::size_of::<FixedNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<FixedNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut FixedNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+        HEAP_USED.fetch_sub(layout.size(), Ordering::Relaxed);
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            match list_index(&layout) {
+                Some(index) => {
+                    let new_node = FixedNode {
+                        next: allocator.list_heads[index].take(),
+                    };
+                    assert!(mem::size_of::<FixedNode>() <= BLOCK_SIZES[index]);
+                    assert!(mem::align_of::<FixedNode>() <= BLOCK_SIZES[index]);
+                    let new_node_ptr = ptr as *mut FixedNode;
+                    new_node_ptr.write(new_node);
+                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+                None => {
+                    let ptr = NonNull::new(ptr).unwrap();
+                    allocator.fallback_allocator.deallocate(ptr, layout);
+                }
             }
-        }
+        })
     }
 }
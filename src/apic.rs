@@ -0,0 +1,112 @@
+//! Local APIC access, used to identify the current CPU and to send
+//! inter-processor interrupts. The 8259 PICs keep handling device IRQs.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Virtual address of the local APIC registers, 0 until `init` ran.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Makes the local APIC registers at `phys` accessible through the physical
+/// memory mapping, mapping the page if the bootloader did not.
+pub fn init(
+    phys: PhysAddr,
+    physical_memory_offset: VirtAddr,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let virt = physical_memory_offset + phys.as_u64();
+    if mapper.translate_addr(virt).is_none() {
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let frame = PhysFrame::containing_address(phys);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("failed to map local APIC")
+                .flush();
+        }
+    }
+    BASE.store(virt.as_u64(), Ordering::SeqCst);
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+fn read(reg: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed) as usize;
+    unsafe { core::ptr::read_volatile((base + reg) as *const u32) }
+}
+
+fn write(reg: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed) as usize;
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u32, value) }
+}
+
+/// Software-enables the local APIC of the current CPU.
+pub fn enable() {
+    write(REG_SPURIOUS, SPURIOUS_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+// The two ICR writes must not be interleaved with an IPI sent by an
+// interrupt handler.
+fn send_ipi(apic_id: u8, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+pub fn send_fixed(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI; the target starts executing in real mode at
+/// `page * 0x1000`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+/// Busy-waits for roughly `us` microseconds. Every write to the POST port
+/// takes about a microsecond, which is precise enough for the startup
+/// protocol.
+pub fn delay_us(us: u32) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..us {
+        unsafe { port.write(0) };
+    }
+}
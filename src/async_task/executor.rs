@@ -1,13 +1,27 @@
+use super::info::{self, Registry, TaskInfo, TaskMeta, TaskState};
+use super::join;
+use super::supervisor::{self, Restart, RestartPolicy};
+use super::JoinHandle;
+use super::Priority;
 use super::Task;
 use super::TaskId;
+use crate::{smp, warn};
 use alloc::collections::VecDeque;
+use alloc::string::String;
 use alloc::task::Wake;
+use alloc::vec::Vec;
 use alloc::{collections::BTreeMap, sync::Arc};
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::task::RawWaker;
 use core::task::RawWakerVTable;
 use core::task::Waker;
 use core::task::{Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
 pub struct SimpleExecutor {
     task_queue: VecDeque<Task>,
@@ -52,67 +66,571 @@ impl SimpleExecutor {
     }
 }
 
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+
+// Executors that are running, one per CPU; idle ones steal new tasks from
+// the others.
+static EXECUTORS: Mutex<Vec<Arc<Shared>>> = Mutex::new(Vec::new());
+
+pub const DEFAULT_CAPACITY: usize = 1024;
+pub const DEFAULT_POLL_BUDGET: usize = 4;
+
+/// Spawns a task on the first executor created on the bootstrap processor.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    SPAWNER
+        .try_get()
+        .expect("no executor has been created")
+        .spawn(future)
+}
+
+/// Lists the tasks of the first executor created on the bootstrap
+/// processor.
+pub fn tasks() -> Vec<TaskInfo> {
+    SPAWNER
+        .try_get()
+        .expect("no executor has been created")
+        .tasks()
+}
+
+/// The number of live tasks across all running executors.
+pub fn live_task_count() -> usize {
+    interrupts::without_interrupts(|| {
+        EXECUTORS
+            .lock()
+            .iter()
+            .map(|shared| shared.live_tasks.load(Ordering::Relaxed))
+            .sum()
+    })
+}
+
+#[derive(Debug)]
+pub struct SpawnError<F>(pub F);
+
+// Ready queues, one per priority class, that never allocate when pushed to:
+// the executor reserves room for every live task up front, and a task is
+// queued at most once at a time.
+struct ReadyQueue {
+    queues: Mutex<[VecDeque<TaskId>; Priority::COUNT]>,
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        ReadyQueue {
+            queues: Mutex::new([VecDeque::new(), VecDeque::new(), VecDeque::new()]),
+        }
+    }
+
+    fn push(&self, task_id: TaskId, priority: Priority) {
+        interrupts::without_interrupts(|| self.queues.lock()[priority.index()].push_back(task_id));
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        interrupts::without_interrupts(|| {
+            self.queues
+                .lock()
+                .iter_mut()
+                .find_map(|queue| queue.pop_front())
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.queues.lock().iter().all(|queue| queue.is_empty()))
+    }
+
+    fn reserve(&self, tasks: usize) {
+        interrupts::without_interrupts(|| {
+            for queue in self.queues.lock().iter_mut() {
+                let additional = tasks.saturating_sub(queue.len());
+                queue.reserve(additional);
+            }
+        });
+    }
+}
+
+struct Shared {
+    new_tasks: Mutex<VecDeque<Task>>,
+    ready: ReadyQueue,
+    live_tasks: AtomicUsize,
+    capacity: usize,
+    slot_waiters: Mutex<VecDeque<Waker>>,
+    registry: Mutex<Registry>,
+    cpu: AtomicUsize,
+}
+
+impl Shared {
+    fn push_task(&self, task: Task) {
+        self.live_tasks.fetch_add(1, Ordering::Relaxed);
+        self.queue_task(task);
+    }
+
+    // Other CPUs are woken as well, since they may steal the task.
+    fn queue_task(&self, task: Task) {
+        interrupts::without_interrupts(|| self.new_tasks.lock().push_back(task));
+        smp::wake_others();
+    }
+
+    fn try_reserve_slot(&self) -> bool {
+        let mut live = self.live_tasks.load(Ordering::Relaxed);
+        loop {
+            if live >= self.capacity {
+                return false;
+            }
+            match self.live_tasks.compare_exchange_weak(
+                live,
+                live + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => live = current,
+            }
+        }
+    }
+
+    fn release_slot(&self) {
+        self.live_tasks.fetch_sub(1, Ordering::Relaxed);
+        let waiter = interrupts::without_interrupts(|| self.slot_waiters.lock().pop_front());
+        if let Some(waker) = waiter {
+            waker.wake();
+        }
+    }
+}
+
+/// Cloneable handle that queues tasks for an `Executor`, usable from within
+/// tasks that run on it.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    /// Spawns `future` regardless of the executor's capacity.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.shared.push_task(task);
+        handle
+    }
+
+    /// Spawns `future` if the executor is below its capacity, otherwise
+    /// hands the future back.
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError<F>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        if !self.shared.try_reserve_slot() {
+            return Err(SpawnError(future));
+        }
+        let (task, handle) = join::joinable(future);
+        self.shared.queue_task(task);
+        Ok(handle)
+    }
+
+    /// Waits until the executor has room for another task, then spawns
+    /// `future`.
+    pub async fn spawn_when_ready<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        SlotWait {
+            shared: &self.shared,
+        }
+        .await;
+        let (task, handle) = join::joinable(future);
+        self.shared.queue_task(task);
+        handle
+    }
+
+    pub fn live_tasks(&self) -> usize {
+        self.shared.live_tasks.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        info::list(&self.shared.registry)
+    }
+}
+
+/// Configures a task before spawning it.
+///
+/// ```ignore
+/// Builder::new().priority(Priority::BottomHalf).spawn(kb::print_keypresses());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Builder {
+    name: Option<String>,
+    priority: Priority,
+    restart: RestartPolicy,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            name: None,
+            priority: Priority::default(),
+            restart: RestartPolicy::Never,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// How a service spawned with `spawn_service` is restarted after it
+    /// panics on a supervised executor.
+    pub fn restart(mut self, policy: RestartPolicy) -> Self {
+        self.restart = policy;
+        self
+    }
+
+    /// Spawns a long-lived service task on `spawner`. `factory` creates the
+    /// service's future, and is called again for every restart.
+    pub fn spawn_service<F, Fut>(self, spawner: &Spawner, factory: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let Builder {
+            name,
+            priority,
+            restart,
+        } = self;
+        let factory = Arc::new(move || {
+            let mut task = Task::new(factory());
+            task.name = name.clone();
+            task.priority = priority;
+            task
+        });
+        let mut task = factory();
+        task.restart = Some(Restart {
+            policy: restart,
+            restarts: 0,
+            factory,
+        });
+        spawner.shared.push_task(task);
+    }
+
+    /// Spawns on the first executor that was created, like `spawn`.
+    pub fn spawn<F>(self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let spawner = SPAWNER.try_get().expect("no executor has been created");
+        self.spawn_on(spawner, future)
+    }
+
+    pub fn spawn_on<F>(self, spawner: &Spawner, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.build(future);
+        spawner.shared.push_task(task);
+        handle
+    }
+
+    fn build<F>(self, future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (mut task, handle) = join::joinable(future);
+        task.name = self.name;
+        task.priority = self.priority;
+        (task, handle)
+    }
+}
+
+// Resolves once a task slot has been reserved for the caller.
+struct SlotWait<'a> {
+    shared: &'a Shared,
+}
+
+impl Future for SlotWait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.shared.try_reserve_slot() {
+            return Poll::Ready(());
+        }
+        interrupts::without_interrupts(|| {
+            self.shared
+                .slot_waiters
+                .lock()
+                .push_back(cx.waker().clone())
+        });
+        // A slot may have been released before the waker was registered.
+        if self.shared.try_reserve_slot() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    poll_budget: usize,
+    round_polls: BTreeMap<TaskId, usize>,
+    deferred: Vec<TaskId>,
+    supervised: bool,
 }
 
 impl Executor {
     pub fn new() -> Self {
-        Executor {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Creates an executor that accepts at most `capacity` live tasks through
+    /// `Spawner::try_spawn` and `Spawner::spawn_when_ready`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let executor = Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            shared: Arc::new(Shared {
+                new_tasks: Mutex::new(VecDeque::new()),
+                ready: ReadyQueue::new(),
+                live_tasks: AtomicUsize::new(0),
+                capacity,
+                slot_waiters: Mutex::new(VecDeque::new()),
+                registry: Mutex::new(Registry::new()),
+                cpu: AtomicUsize::new(smp::cpu_index()),
+            }),
             waker_cache: BTreeMap::new(),
+            poll_budget: DEFAULT_POLL_BUDGET,
+            round_polls: BTreeMap::new(),
+            deferred: Vec::new(),
+            supervised: false,
+        };
+        if smp::cpu_index() == 0 {
+            let _ = SPAWNER.try_init_once(|| executor.spawner());
         }
+        executor
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        info::list(&self.shared.registry)
+    }
+
+    /// In supervised mode a task that panics is torn down and logged instead
+    /// of halting the kernel, see `supervisor`.
+    pub fn set_supervised(&mut self, supervised: bool) {
+        self.supervised = supervised;
+    }
+
+    /// Sets how often a task may be polled per scheduling round before it
+    /// has to wait for the other ready tasks, whatever their priority.
+    pub fn set_poll_budget(&mut self, budget: usize) {
+        assert!(budget > 0, "poll budget must be non-zero");
+        self.poll_budget = budget;
+    }
+
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.shared.live_tasks.fetch_add(1, Ordering::Relaxed);
+        self.spawn_task(task);
+        handle
+    }
+
+    fn spawn_task(&mut self, mut task: Task) {
         let task_id = task.id;
+        let priority = task.priority;
+        let meta = TaskMeta::new(task_id, task.name.take(), priority);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        self.shared.ready.reserve(self.tasks.len());
+        interrupts::without_interrupts(|| self.shared.registry.lock().insert(meta.clone()));
+        let waker = TaskWaker::new(task_id, priority, meta, self.shared.clone());
+        waker.queued.store(true, Ordering::Release);
+        self.waker_cache.insert(task_id, waker);
+        self.shared.ready.push(task_id, priority);
+    }
+
+    // Takes this executor's share of the new tasks and leaves the rest for
+    // the executors on other CPUs to steal.
+    fn spawn_new_tasks(&mut self) {
+        let executors = interrupts::without_interrupts(|| EXECUTORS.lock().len()).max(1);
+        let share = interrupts::without_interrupts(|| self.shared.new_tasks.lock().len())
+            .div_ceil(executors);
+        for _ in 0..share {
+            match interrupts::without_interrupts(|| self.shared.new_tasks.lock().pop_front()) {
+                Some(task) => self.spawn_task(task),
+                None => break,
+            }
+        }
     }
 
+    // Moves a task that has not been polled yet over from another executor.
+    fn steal_task(&mut self) -> bool {
+        let stolen = interrupts::without_interrupts(|| {
+            EXECUTORS
+                .lock()
+                .iter()
+                .filter(|victim| !Arc::ptr_eq(victim, &self.shared))
+                .find_map(|victim| Some((victim.clone(), victim.new_tasks.lock().pop_back()?)))
+        });
+        match stolen {
+            Some((victim, task)) => {
+                victim.release_slot();
+                self.shared.live_tasks.fetch_add(1, Ordering::Relaxed);
+                self.spawn_task(task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn can_steal(&self) -> bool {
+        EXECUTORS
+            .lock()
+            .iter()
+            .any(|other| !Arc::ptr_eq(other, &self.shared) && !other.new_tasks.lock().is_empty())
+    }
+
+    // Runs one scheduling round: ready tasks are polled highest class first
+    // until all queues are empty. A task that used up its poll budget is set
+    // aside, still marked as queued, and only requeued once the round is over.
     fn run_ready_tasks(&mut self) {
         let Self {
             tasks,
-            task_queue,
+            shared,
             waker_cache,
+            poll_budget,
+            round_polls,
+            deferred,
+            supervised,
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
+        while let Some(task_id) = shared.ready.pop() {
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue,
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
+            let polls = round_polls.entry(task_id).or_insert(0);
+            if *polls >= *poll_budget {
+                deferred.push(task_id);
+                continue;
+            }
+            *polls += 1;
+            let task_waker = &waker_cache[&task_id];
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            let meta = &task_waker.meta;
+            meta.set_state(TaskState::Running);
+            let start = info::cycles();
+            let poll = if *supervised {
+                supervisor::poll(task, &mut context, meta)
+            } else {
+                Ok(task.poll(&mut context))
+            };
+            meta.record_poll(info::cycles().wrapping_sub(start));
+            match poll {
+                Err(supervisor::Panicked) => {
+                    meta.set_state(TaskState::Failed);
+                    let task = tasks.remove(&task_id).unwrap();
+                    // The abandoned poll may have left the future half
+                    // updated, so it is leaked like the poll's frames
+                    // instead of dropped.
+                    mem::forget(task.future);
+                    if let Some(on_panic) = task.on_panic {
+                        on_panic();
+                    }
+                    if let Some(task_waker) = waker_cache.remove(&task_id) {
+                        task_waker.queued.store(true, Ordering::Release);
+                    }
+                    interrupts::without_interrupts(|| shared.registry.lock().finish(task_id));
+                    shared.release_slot();
+                    if let Some(restarted) = task.restart.as_ref().and_then(Restart::next) {
+                        warn!("restarting task {} as task {}", task_id, restarted.id);
+                        shared.push_task(restarted);
+                    }
+                }
+                Ok(Poll::Ready(())) => {
+                    meta.set_state(TaskState::Completed);
                     tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    if let Some(task_waker) = waker_cache.remove(&task_id) {
+                        // Keep stale wakers from queueing a finished task.
+                        task_waker.queued.store(true, Ordering::Release);
+                    }
+                    interrupts::without_interrupts(|| shared.registry.lock().finish(task_id));
+                    shared.release_slot();
+                }
+                Ok(Poll::Pending) => {
+                    // A wake during the poll already queued the task again.
+                    if !task_waker.queued.load(Ordering::Acquire) {
+                        meta.set_state(TaskState::Waiting);
+                    }
                 }
-                Poll::Pending => {}
             }
         }
+
+        for task_id in deferred.drain(..) {
+            if let Some(task) = tasks.get(&task_id) {
+                shared.ready.push(task_id, task.priority);
+            }
+        }
+        round_polls.clear();
     }
 
     pub fn run(&mut self) -> ! {
+        self.shared.cpu.store(smp::cpu_index(), Ordering::Relaxed);
+        interrupts::without_interrupts(|| EXECUTORS.lock().push(self.shared.clone()));
         loop {
+            self.spawn_new_tasks();
+            if self.shared.ready.is_empty() {
+                self.steal_task();
+            }
             self.run_ready_tasks();
             self.sleep();
         }
     }
 
     fn sleep(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts::enable_and_hlt;
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.shared.ready.is_empty()
+            && self.shared.new_tasks.lock().is_empty()
+            && !self.can_steal()
+        {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -122,19 +640,35 @@ impl Executor {
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    meta: Arc<TaskMeta>,
+    queued: AtomicBool,
+    shared: Arc<Shared>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(
+        task_id: TaskId,
+        priority: Priority,
+        meta: Arc<TaskMeta>,
+        shared: Arc<Shared>,
+    ) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
-            task_queue,
-        }))
+            priority,
+            meta,
+            queued: AtomicBool::new(false),
+            shared,
+        })
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        // Repeated wakes before the task is polled again collapse into one.
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.meta.set_state(TaskState::Ready);
+            self.shared.ready.push(self.task_id, self.priority);
+            smp::wake(self.shared.cpu.load(Ordering::Relaxed));
+        }
     }
 }
 
@@ -0,0 +1,170 @@
+use super::{Priority, TaskId};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Finished tasks stay listed, marked as completed, until this many newer
+// ones have finished.
+const FINISHED_HISTORY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// Queued for polling.
+    Ready,
+    /// Currently being polled.
+    Running,
+    /// Returned `Pending` and has not been woken since.
+    Waiting,
+    Completed,
+    /// Panicked and was torn down by a supervised executor.
+    Failed,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Waiting => "waiting",
+            TaskState::Completed => "completed",
+            TaskState::Failed => "failed",
+        })
+    }
+}
+
+impl TaskState {
+    fn from_u8(value: u8) -> TaskState {
+        match value {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            2 => TaskState::Waiting,
+            3 => TaskState::Completed,
+            _ => TaskState::Failed,
+        }
+    }
+}
+
+/// Snapshot of a task's metadata and statistics.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    /// Time spent inside `poll`, in TSC cycles.
+    pub poll_cycles: u64,
+}
+
+pub(crate) struct TaskMeta {
+    id: TaskId,
+    name: Option<String>,
+    priority: Priority,
+    state: AtomicU8,
+    polls: AtomicU64,
+    poll_cycles: AtomicU64,
+}
+
+impl TaskMeta {
+    pub(crate) fn new(id: TaskId, name: Option<String>, priority: Priority) -> Arc<TaskMeta> {
+        Arc::new(TaskMeta {
+            id,
+            name,
+            priority,
+            state: AtomicU8::new(TaskState::Ready as u8),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            priority: self.priority,
+            state: TaskState::from_u8(self.state.load(Ordering::Relaxed)),
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct Registry {
+    tasks: BTreeMap<TaskId, Arc<TaskMeta>>,
+    finished: VecDeque<TaskId>,
+}
+
+impl Registry {
+    pub(crate) const fn new() -> Self {
+        Registry {
+            tasks: BTreeMap::new(),
+            finished: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, meta: Arc<TaskMeta>) {
+        self.tasks.insert(meta.id, meta);
+    }
+
+    pub(crate) fn finish(&mut self, id: TaskId) {
+        self.finished.push_back(id);
+        if self.finished.len() > FINISHED_HISTORY {
+            if let Some(oldest) = self.finished.pop_front() {
+                self.tasks.remove(&oldest);
+            }
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<TaskInfo> {
+        self.tasks.values().map(|meta| meta.info()).collect()
+    }
+}
+
+pub(crate) fn list(registry: &Mutex<Registry>) -> Vec<TaskInfo> {
+    interrupts::without_interrupts(|| registry.lock().list())
+}
+
+pub(crate) fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Writes a `ps`-like table of `tasks`.
+pub fn write_task_table(out: &mut impl fmt::Write, tasks: &[TaskInfo]) -> fmt::Result {
+    writeln!(
+        out,
+        "{:>5} {:<11} {:<9} {:>8} {:>14}  NAME",
+        "ID", "PRIORITY", "STATE", "POLLS", "CYCLES"
+    )?;
+    for task in tasks {
+        writeln!(
+            out,
+            "{:>5} {:<11} {:<9} {:>8} {:>14}  {}",
+            task.id,
+            task.priority,
+            task.state,
+            task.polls,
+            task.poll_cycles,
+            task.name.as_deref().unwrap_or("-")
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use super::{Task, TaskId};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Cancelled,
+    /// The task panicked while running on a supervised executor.
+    Panicked,
+}
+
+enum Stage<T> {
+    Running,
+    Finished(T),
+    Cancelled,
+    Panicked,
+    Consumed,
+}
+
+struct Shared<T> {
+    stage: Stage<T>,
+    aborted: bool,
+    join_waker: Option<Waker>,
+    task_waker: Option<Waker>,
+}
+
+/// Handle to a spawned task which resolves to the task's output.
+///
+/// Dropping the handle detaches the task: it keeps running, but its output
+/// is discarded.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Cancels the task. It is dropped the next time the executor gets to
+    /// it and the handle resolves to `Err(JoinError::Cancelled)`.
+    pub fn abort(&self) {
+        let waker = {
+            let mut shared = self.shared.lock();
+            shared.aborted = true;
+            shared.task_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    pub fn detach(self) {}
+
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.shared.lock().stage, Stage::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock();
+        match mem::replace(&mut shared.stage, Stage::Consumed) {
+            Stage::Finished(output) => Poll::Ready(Ok(output)),
+            Stage::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            Stage::Panicked => Poll::Ready(Err(JoinError::Panicked)),
+            Stage::Running => {
+                shared.stage = Stage::Running;
+                shared.join_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Stage::Consumed => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+struct Joinable<F: Future> {
+    future: Pin<Box<F>>,
+    shared: Arc<Mutex<Shared<F::Output>>>,
+}
+
+impl<F: Future> Joinable<F> {
+    fn complete(&self, stage: Stage<F::Output>) {
+        complete(&self.shared, stage);
+    }
+}
+
+fn complete<T>(shared: &Mutex<Shared<T>>, stage: Stage<T>) {
+    let waker = {
+        let mut shared = shared.lock();
+        shared.stage = stage;
+        shared.task_waker = None;
+        shared.join_waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        {
+            let mut shared = self.shared.lock();
+            if shared.aborted {
+                drop(shared);
+                self.complete(Stage::Cancelled);
+                return Poll::Ready(());
+            }
+            match &shared.task_waker {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => shared.task_waker = Some(cx.waker().clone()),
+            }
+        }
+
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(output) => {
+                self.complete(Stage::Finished(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Wraps `future` into a `Task` whose output is delivered to the returned
+/// `JoinHandle`.
+pub(crate) fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let shared = Arc::new(Mutex::new(Shared {
+        stage: Stage::Running,
+        aborted: false,
+        join_waker: None,
+        task_waker: None,
+    }));
+    let mut task = Task::new(Joinable {
+        future: Box::pin(future),
+        shared: shared.clone(),
+    });
+    let panicked = shared.clone();
+    task.on_panic = Some(Box::new(move || complete(&panicked, Stage::Panicked)));
+    let id = task.id;
+    (task, JoinHandle { id, shared })
+}
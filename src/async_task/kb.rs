@@ -1,29 +1,57 @@
 use crate::print;
-use crate::println;
+use crate::vga_buffer;
+use crate::warn;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::Stream;
 use futures_util::stream::StreamExt;
 use futures_util::task::AtomicWaker;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+// Characters typed, buffered for `read_char`.
+static CHAR_QUEUE: OnceCell<ArrayQueue<char>> = OnceCell::uninit();
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+// Lock keys as toggled through `print_keypresses`; Num Lock starts on, like
+// in the decoder.
+static CAPS_LOCK: AtomicBool = AtomicBool::new(false);
+static NUM_LOCK: AtomicBool = AtomicBool::new(true);
+static SCROLL_LOCK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+pub fn lock_state() -> LockState {
+    LockState {
+        caps_lock: CAPS_LOCK.load(Ordering::Relaxed),
+        num_lock: NUM_LOCK.load(Ordering::Relaxed),
+        scroll_lock: SCROLL_LOCK.load(Ordering::Relaxed),
+    }
+}
+
+// Lines Shift+PageUp/PageDown scroll the console by.
+const SCROLL_LINES: usize = 12;
+
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            warn!("scancode queue full; dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        warn!("scancode queue uninitialized");
     }
 }
 
@@ -36,6 +64,7 @@ impl ScancodeStream {
         SCANCODE_QUEUE
             .try_init_once(|| ArrayQueue::new(100))
             .expect("ScancodeStream::new should only be called once");
+        CHAR_QUEUE.init_once(|| ArrayQueue::new(100));
         ScancodeStream { _private: () }
     }
 }
@@ -71,14 +100,61 @@ pub async fn print_keypresses() {
         HandleControl::Ignore,
     );
 
+    let (mut shift, mut alt) = (false, false);
+
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            match key_event.code {
+                KeyCode::LShift | KeyCode::RShift => shift = key_event.state != KeyState::Up,
+                KeyCode::LAlt | KeyCode::RAltGr => alt = key_event.state != KeyState::Up,
+                KeyCode::CapsLock if key_event.state == KeyState::Down => toggle(&CAPS_LOCK),
+                KeyCode::NumpadLock if key_event.state == KeyState::Down => toggle(&NUM_LOCK),
+                KeyCode::ScrollLock if key_event.state == KeyState::Down => toggle(&SCROLL_LOCK),
+                _ => {}
+            }
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::RawKey(KeyCode::PageUp) if shift => {
+                        vga_buffer::scroll_up(SCROLL_LINES)
+                    }
+                    DecodedKey::RawKey(KeyCode::PageDown) if shift => {
+                        vga_buffer::scroll_down(SCROLL_LINES)
+                    }
+                    DecodedKey::RawKey(key) if alt && console_key(key).is_some() => {
+                        vga_buffer::switch_console(console_key(key).unwrap())
+                    }
+                    DecodedKey::Unicode(character) => {
+                        let console = vga_buffer::active_console();
+                        vga_buffer::write_console(console, format_args!("{}", character));
+                        // The oldest characters are dropped if nobody reads them.
+                        CHAR_QUEUE.get().unwrap().force_push(character);
+                    }
                     DecodedKey::RawKey(key) => print!("{:?}", key),
                 }
             }
         }
     }
 }
+
+fn toggle(lock: &AtomicBool) {
+    lock.fetch_xor(true, Ordering::Relaxed);
+}
+
+// The console Alt plus `key` switches to.
+fn console_key(key: KeyCode) -> Option<usize> {
+    let keys = [
+        KeyCode::F1,
+        KeyCode::F2,
+        KeyCode::F3,
+        KeyCode::F4,
+        KeyCode::F5,
+        KeyCode::F6,
+    ];
+    keys.iter().position(|&k| k == key)
+}
+
+/// Takes the oldest character typed that has not been read yet. Only
+/// characters decoded by `print_keypresses` are buffered.
+pub fn read_char() -> Option<char> {
+    CHAR_QUEUE.try_get().ok()?.pop()
+}
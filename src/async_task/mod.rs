@@ -1,22 +1,72 @@
 use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt;
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 pub mod executor;
+pub mod info;
+pub mod join;
 pub mod kb;
+pub mod supervisor;
+pub mod sync;
 use core::sync::atomic::{AtomicU64, Ordering};
 
+pub use executor::{spawn, tasks, Builder, Spawner};
+pub use info::{TaskInfo, TaskState};
+pub use join::{JoinError, JoinHandle};
+pub use supervisor::RestartPolicy;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
+
+/// Scheduling class of a task. The executor always polls ready tasks of a
+/// higher class first, but every class gets its turn in each round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// Deferred work of interrupt handlers, like decoding scancodes.
+    BottomHalf = 0,
+    #[default]
+    Interactive = 1,
+    Background = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Priority::BottomHalf => "bottom-half",
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+        })
+    }
+}
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    name: Option<String>,
+    priority: Priority,
+    restart: Option<supervisor::Restart>,
+    // Tells whoever waits for the task that it panicked, since it is leaked
+    // rather than dropped then.
+    on_panic: Option<Box<dyn FnOnce() + Send>>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            name: None,
+            priority: Priority::default(),
+            restart: None,
+            on_panic: None,
             future: Box::pin(future),
         }
     }
@@ -30,4 +80,14 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
 }
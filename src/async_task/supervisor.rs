@@ -0,0 +1,133 @@
+//! Supervised polling: a panic inside a task's `poll` tears down only that
+//! task instead of halting the kernel.
+//!
+//! The kernel is built with `panic = "abort"`, so there is no unwinding.
+//! Instead the executor records its own frame before polling and the panic
+//! handler jumps straight back to it via `recover_from_panic`. The frames of
+//! the failed poll are abandoned: whatever they owned is leaked, and spin
+//! locks they held stay locked.
+
+use super::info::TaskMeta;
+use super::{Task, TaskId};
+use crate::context::{self, JumpBuffer};
+use crate::thread::{self, ThreadId};
+use crate::{emergency, println, smp};
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    Always,
+    /// Restart at most this many times.
+    Limited(u32),
+}
+
+// How to bring a service task back after it panicked.
+pub(crate) struct Restart {
+    pub(crate) policy: RestartPolicy,
+    pub(crate) restarts: u32,
+    pub(crate) factory: Arc<dyn Fn() -> Task + Send + Sync>,
+}
+
+impl Restart {
+    /// Creates the replacement task, or `None` if the policy is exhausted.
+    pub(crate) fn next(&self) -> Option<Task> {
+        let allowed = match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::Limited(max) => self.restarts < max,
+        };
+        if !allowed {
+            return None;
+        }
+        let mut task = (self.factory)();
+        task.restart = Some(Restart {
+            policy: self.policy,
+            restarts: self.restarts + 1,
+            factory: self.factory.clone(),
+        });
+        Some(task)
+    }
+}
+
+struct PollGuard {
+    buf: JumpBuffer,
+    task_id: TaskId,
+    meta: *const TaskMeta,
+    // The thread polling, on the bootstrap processor, where the timer may
+    // switch to other threads in the middle of the poll.
+    thread: Option<ThreadId>,
+}
+
+// The guarded poll running on each CPU.
+static CURRENT: [AtomicPtr<PollGuard>; smp::MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<PollGuard> = AtomicPtr::new(ptr::null_mut());
+    [NONE; smp::MAX_CPUS]
+};
+
+pub(crate) struct Panicked;
+
+/// Polls `task`, returning `Err(Panicked)` if the poll panicked.
+pub(crate) fn poll(
+    task: &mut Task,
+    cx: &mut Context,
+    meta: &TaskMeta,
+) -> Result<Poll<()>, Panicked> {
+    let mut guard = PollGuard {
+        buf: JumpBuffer::default(),
+        task_id: task.id,
+        meta,
+        thread: (smp::cpu_index() == 0).then(thread::current),
+    };
+    let interrupts_enabled = interrupts::are_enabled();
+    let mut poll = Poll::Pending;
+
+    let current = &CURRENT[smp::cpu_index()];
+    let previous = current.swap(&mut guard, Ordering::SeqCst);
+    let buf: *mut JumpBuffer = &mut guard.buf;
+    let completed = unsafe { context::catch(buf, || poll = task.poll(cx)) };
+    current.store(previous, Ordering::SeqCst);
+
+    if completed {
+        Ok(poll)
+    } else {
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+        Err(Panicked)
+    }
+}
+
+/// Called by the panic handler. If the panic happened while a supervised
+/// task was being polled, logs it and resumes the executor; otherwise it
+/// returns and the handler carries on as usual. Panics in interrupt handlers
+/// that interrupted a poll, or in other threads running while the polling
+/// one was preempted, are not the task's doing and are not recovered from.
+pub fn recover_from_panic(info: &PanicInfo) {
+    if crate::interrupts::in_interrupt_handler() {
+        return;
+    }
+    let current = &CURRENT[smp::cpu_index()];
+    let guard = current.load(Ordering::SeqCst);
+    // Another thread may have panicked while the poll was switched out.
+    if guard.is_null() || unsafe { (*guard).thread }.is_some_and(|t| t != thread::current()) {
+        return;
+    }
+    current.store(ptr::null_mut(), Ordering::SeqCst);
+
+    // The task may have panicked in the middle of printing.
+    emergency::release_current_cpu();
+    let guard = unsafe { &*guard };
+    let name = unsafe { (*guard.meta).name() }.unwrap_or("-");
+    println!(
+        "ERROR: task {} ({}) panicked: {}",
+        guard.task_id, name, info
+    );
+    unsafe { context::resume(&guard.buf) }
+}
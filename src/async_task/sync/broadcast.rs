@@ -0,0 +1,125 @@
+use super::{with_lock, WaitList};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    Closed,
+    /// The receiver fell behind and this many messages were overwritten.
+    Lagged(u64),
+}
+
+struct Inner<T> {
+    // Messages still retained, the oldest having sequence number `head_seq`.
+    buffer: VecDeque<T>,
+    head_seq: u64,
+    capacity: usize,
+    senders: usize,
+    waiters: WaitList,
+}
+
+impl<T> Inner<T> {
+    fn next_seq(&self) -> u64 {
+        self.head_seq + self.buffer.len() as u64
+    }
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+/// Receives every message sent after it subscribed.
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+    next: u64,
+}
+
+/// Creates a channel retaining the last `capacity` messages for receivers
+/// that have not seen them yet.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be non-zero");
+    let inner = Arc::new(Mutex::new(Inner {
+        buffer: VecDeque::with_capacity(capacity),
+        head_seq: 0,
+        capacity,
+        senders: 1,
+        waiters: WaitList::new(),
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner, next: 0 },
+    )
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to all receivers, overwriting the oldest retained
+    /// message if the buffer is full.
+    pub fn send(&self, value: T) {
+        with_lock(&self.inner, |inner| {
+            if inner.buffer.len() == inner.capacity {
+                inner.buffer.pop_front();
+                inner.head_seq += 1;
+            }
+            inner.buffer.push_back(value);
+            inner.waiters.wake_all();
+        });
+    }
+
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = with_lock(&self.inner, |inner| inner.next_seq());
+        Receiver {
+            inner: self.inner.clone(),
+            next,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        with_lock(&self.inner, |inner| inner.senders += 1);
+        Sender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        with_lock(&self.inner, |inner| {
+            inner.senders -= 1;
+            inner.waiters.wake_all();
+        });
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let inner = self.inner.clone();
+        with_lock(&inner, |inner| {
+            if self.next < inner.head_seq {
+                let missed = inner.head_seq - self.next;
+                self.next = inner.head_seq;
+                return Poll::Ready(Err(RecvError::Lagged(missed)));
+            }
+            if self.next < inner.next_seq() {
+                let value = inner.buffer[(self.next - inner.head_seq) as usize].clone();
+                self.next += 1;
+                return Poll::Ready(Ok(value));
+            }
+            if inner.senders == 0 {
+                return Poll::Ready(Err(RecvError::Closed));
+            }
+            inner.waiters.register(cx.waker());
+            Poll::Pending
+        })
+    }
+}
@@ -0,0 +1,52 @@
+//! Async synchronisation primitives for tasks running on the `Executor`.
+//!
+//! All shared state is guarded by spin locks taken with interrupts disabled,
+//! so the operations that neither wait nor allocate (`try_send` on a bounded
+//! `mpsc` channel, `broadcast::Sender::send`, `Semaphore::add_permits`) may
+//! also be used from interrupt handlers.
+
+use alloc::collections::VecDeque;
+use core::task::Waker;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod mutex;
+pub mod oneshot;
+pub mod semaphore;
+
+pub use mutex::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+
+fn with_lock<T, R>(lock: &Mutex<T>, f: impl FnOnce(&mut MutexGuard<T>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut lock.lock()))
+}
+
+// Wakers of tasks blocked on a primitive. Every waiter is woken when the
+// state changes and re-checks its condition, so a waiter that is dropped
+// after being woken never swallows a wakeup meant for someone else.
+struct WaitList {
+    wakers: VecDeque<Waker>,
+}
+
+impl WaitList {
+    const fn new() -> Self {
+        WaitList {
+            wakers: VecDeque::new(),
+        }
+    }
+
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push_back(waker.clone());
+        }
+    }
+
+    // Drains in place so that waking never frees memory.
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use super::{with_lock, WaitList};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+    send_waiters: WaitList,
+}
+
+/// Sending half of a channel; clone it to get more producers.
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+/// Creates a channel holding at most `capacity` queued messages. `send`
+/// waits for room once it is full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be non-zero");
+    new_channel(Some(capacity))
+}
+
+/// Creates a channel whose queue grows as needed, so sending never waits.
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None)
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    // A bounded queue is allocated up front so `try_send` never allocates.
+    let inner = Arc::new(Mutex::new(Inner {
+        queue: VecDeque::with_capacity(capacity.unwrap_or(0)),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        receiver_waker: None,
+        send_waiters: WaitList::new(),
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let waker = with_lock(&self.inner, |inner| {
+            if !inner.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if let Some(capacity) = inner.capacity {
+                if inner.queue.len() >= capacity {
+                    return Err(TrySendError::Full(value));
+                }
+            }
+            inner.queue.push_back(value);
+            Ok(inner.receiver_waker.take())
+        })?;
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Sends `value`, waiting for room in a bounded channel.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        poll_fn(|cx| match self.try_send(value.take().unwrap()) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(v)) => Poll::Ready(Err(SendError(v))),
+            Err(TrySendError::Full(v)) => {
+                value = Some(v);
+                with_lock(&self.inner, |inner| {
+                    inner.send_waiters.register(cx.waker());
+                    // The receiver may have made room before we registered.
+                    if !matches!(inner.capacity, Some(c) if inner.queue.len() >= c) {
+                        cx.waker().wake_by_ref();
+                    }
+                });
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        with_lock(&self.inner, |inner| !inner.receiver_alive)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        with_lock(&self.inner, |inner| inner.senders += 1);
+        Sender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = with_lock(&self.inner, |inner| {
+            inner.senders -= 1;
+            if inner.senders == 0 {
+                inner.receiver_waker.take()
+            } else {
+                None
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next message, or `None` once every sender is gone and
+    /// the queue is drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        with_lock(&self.inner, |inner| {
+            let value = inner.queue.pop_front();
+            inner.send_waiters.wake_all();
+            value
+        })
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        with_lock(&self.inner, |inner| match inner.queue.pop_front() {
+            Some(value) => {
+                inner.send_waiters.wake_all();
+                Poll::Ready(Some(value))
+            }
+            None if inner.senders == 0 => Poll::Ready(None),
+            None => {
+                inner.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        with_lock(&self.inner, |inner| {
+            inner.receiver_alive = false;
+            inner.queue.clear();
+            inner.send_waiters.wake_all();
+        });
+    }
+}
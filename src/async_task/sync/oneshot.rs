@@ -0,0 +1,103 @@
+use super::with_lock;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Inner<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+/// Resolves to the sent value, or `RecvError` if the sender was dropped
+/// without sending.
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        waker: None,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, handing it back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = with_lock(&self.inner, |inner| {
+            if !inner.receiver_alive {
+                return Err(value);
+            }
+            inner.value = Some(value);
+            Ok(inner.waker.take())
+        })?;
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        with_lock(&self.inner, |inner| !inner.receiver_alive)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = with_lock(&self.inner, |inner| {
+            inner.sender_alive = false;
+            inner.waker.take()
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Option<T> {
+        with_lock(&self.inner, |inner| inner.value.take())
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        with_lock(&self.inner, |inner| {
+            if let Some(value) = inner.value.take() {
+                Poll::Ready(Ok(value))
+            } else if !inner.sender_alive {
+                Poll::Ready(Err(RecvError))
+            } else {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        with_lock(&self.inner, |inner| inner.receiver_alive = false);
+    }
+}
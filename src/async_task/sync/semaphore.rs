@@ -0,0 +1,80 @@
+use super::{with_lock, WaitList};
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+struct State {
+    permits: usize,
+    waiters: WaitList,
+}
+
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+/// A permit acquired from a `Semaphore`, returned to it when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        with_lock(&self.state, |state| state.permits)
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        with_lock(&self.state, |state| {
+            if state.permits > 0 {
+                state.permits -= 1;
+                Some(SemaphorePermit { semaphore: self })
+            } else {
+                None
+            }
+        })
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        poll_fn(|cx| self.poll_acquire(cx)).await
+    }
+
+    fn poll_acquire(&self, cx: &mut Context) -> Poll<SemaphorePermit<'_>> {
+        with_lock(&self.state, |state| {
+            if state.permits > 0 {
+                state.permits -= 1;
+                Poll::Ready(SemaphorePermit { semaphore: self })
+            } else {
+                state.waiters.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
+
+    pub fn add_permits(&self, permits: usize) {
+        with_lock(&self.state, |state| {
+            state.permits += permits;
+            state.waiters.wake_all();
+        });
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Consumes the permit without returning it to the semaphore.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
@@ -0,0 +1,138 @@
+//! Saving and restoring the callee-saved register state, used to get back
+//! into a known kernel frame from a deeper, abandoned one and to switch
+//! between the stacks of kernel threads.
+
+use core::arch::global_asm;
+
+/// Callee-saved registers plus stack pointer and return address of a
+/// `catch` call.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct JumpBuffer {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+extern "C" {
+    fn context_catch(f: extern "C" fn(*mut u8), data: *mut u8, buf: *mut JumpBuffer) -> u64;
+    fn context_resume(buf: *const JumpBuffer) -> !;
+    fn context_switch(old_rsp: *mut u64, new_rsp: u64);
+}
+
+global_asm!(
+    ".global context_catch",
+    "context_catch:",
+    "mov [rdx], rbx",
+    "mov [rdx + 8], rbp",
+    "mov [rdx + 16], r12",
+    "mov [rdx + 24], r13",
+    "mov [rdx + 32], r14",
+    "mov [rdx + 40], r15",
+    "lea rax, [rsp + 8]",
+    "mov [rdx + 48], rax",
+    "mov rax, [rsp]",
+    "mov [rdx + 56], rax",
+    // Keep the stack 16-byte aligned for the call.
+    "sub rsp, 8",
+    "mov rax, rdi",
+    "mov rdi, rsi",
+    "call rax",
+    "add rsp, 8",
+    "xor eax, eax",
+    "ret",
+    "",
+    ".global context_resume",
+    "context_resume:",
+    "mov rbx, [rdi]",
+    "mov rbp, [rdi + 8]",
+    "mov r12, [rdi + 16]",
+    "mov r13, [rdi + 24]",
+    "mov r14, [rdi + 32]",
+    "mov r15, [rdi + 40]",
+    "mov rsp, [rdi + 48]",
+    "mov eax, 1",
+    "jmp qword ptr [rdi + 56]",
+    "",
+    ".global context_switch",
+    "context_switch:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+/// Runs `f`, recording the current frame in `buf`. Returns `true` if `f`
+/// returned normally and `false` if `resume(buf)` was called while `f` was
+/// running.
+///
+/// # Safety
+///
+/// `buf` must stay valid until `catch` returns. When resumed, the frames of
+/// `f` are abandoned without running destructors, so anything they own is
+/// leaked and any lock they hold stays locked.
+pub unsafe fn catch<F: FnOnce()>(buf: *mut JumpBuffer, f: F) -> bool {
+    extern "C" fn trampoline<F: FnOnce()>(data: *mut u8) {
+        let f = unsafe { (*(data as *mut Option<F>)).take().unwrap() };
+        f();
+    }
+
+    let mut f = Some(f);
+    context_catch(trampoline::<F>, &mut f as *mut Option<F> as *mut u8, buf) == 0
+}
+
+/// Returns from the `catch` call that recorded `buf`, with `false`.
+///
+/// # Safety
+///
+/// Must only be called from code running inside the `f` of that `catch`
+/// call.
+pub unsafe fn resume(buf: *const JumpBuffer) -> ! {
+    context_resume(buf)
+}
+
+/// Pushes the callee-saved registers, stores the stack pointer in
+/// `*old_rsp` and continues on the stack saved at `new_rsp`. Returns once
+/// another `switch` comes back to the stored stack pointer.
+///
+/// # Safety
+///
+/// `new_rsp` must come from a `switch` that has not been resumed yet or from
+/// `init_stack`, and its stack must still be alive.
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    context_switch(old_rsp, new_rsp)
+}
+
+/// Prepares the stack ending at `stack_end` so that switching to the
+/// returned stack pointer calls `entry`.
+///
+/// # Safety
+///
+/// The 64 bytes below `stack_end` must be writable.
+pub unsafe fn init_stack(stack_end: u64, entry: extern "C" fn() -> !) -> u64 {
+    let top = (stack_end & !0xf) as *mut u64;
+    // Return address of `entry`, which never returns.
+    top.sub(1).write(0);
+    top.sub(2).write(entry as usize as u64);
+    // rbx, rbp and r12 to r15, all zero.
+    for i in 3..9 {
+        top.sub(i).write(0);
+    }
+    top.sub(8) as u64
+}
@@ -0,0 +1,97 @@
+//! The kernel's message buffer: everything printed with `print!` or
+//! `serial_print!`, and every log record, is also kept in a ring buffer
+//! here, from the first message on, so it can be read back with `read`
+//! after it scrolled away, and is dumped over serial on panic.
+//!
+//! Writers take their place in the ring with a single atomic add and never
+//! wait for each other, so recording works from interrupt handlers and on
+//! any CPU. Output of concurrent writers may interleave, and a reader racing
+//! with a writer may see part of a message still missing.
+
+use crate::serial;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use uart_16550::SerialPort;
+
+/// Bytes kept; older ones are overwritten.
+pub const DMESG_SIZE: usize = 16 * 1024;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicU8 = AtomicU8::new(0);
+static BUFFER: [AtomicU8; DMESG_SIZE] = [EMPTY; DMESG_SIZE];
+// Bytes ever written, including the place reserved by writers not done yet.
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Appends `bytes` to the buffer.
+pub fn write(bytes: &[u8]) {
+    let start = HEAD.fetch_add(bytes.len(), Ordering::AcqRel);
+    // Only the last `DMESG_SIZE` bytes of a long write survive anyway.
+    let skip = bytes.len().saturating_sub(DMESG_SIZE);
+    for (i, &byte) in bytes.iter().enumerate().skip(skip) {
+        BUFFER[(start + i) % DMESG_SIZE].store(byte, Ordering::Release);
+    }
+}
+
+/// Appends formatted output, as `print!` and `serial_print!` do.
+pub fn record(args: fmt::Arguments) {
+    struct Recorder;
+
+    impl fmt::Write for Recorder {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            write(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let _ = fmt::write(&mut Recorder, args);
+}
+
+/// How many bytes were written since boot, including those overwritten.
+pub fn written() -> usize {
+    HEAD.load(Ordering::Acquire)
+}
+
+/// Copies the newest bytes that fit into `out`, oldest first, and returns
+/// how many there are.
+pub fn read(out: &mut [u8]) -> usize {
+    let end = written();
+    let len = end.min(DMESG_SIZE).min(out.len());
+    let start = end - len;
+    for (i, byte) in out[..len].iter_mut().enumerate() {
+        *byte = BUFFER[(start + i) % DMESG_SIZE].load(Ordering::Acquire);
+    }
+    // Bytes overwritten while they were copied are dropped.
+    let overwritten = (written().saturating_sub(DMESG_SIZE))
+        .saturating_sub(start)
+        .min(len);
+    out.copy_within(overwritten..len, 0);
+    len - overwritten
+}
+
+/// Sends the whole buffer out of the first serial port, without taking the
+/// lock of `serial::SERIAL1`, which the panicking code may hold.
+pub fn dump_to_serial() {
+    let mut port = unsafe { SerialPort::new(serial::COM1) };
+    let end = written();
+    for position in end.saturating_sub(DMESG_SIZE)..end {
+        port.send(BUFFER[position % DMESG_SIZE].load(Ordering::Acquire));
+    }
+}
+
+#[test_case]
+fn test_dmesg() {
+    crate::print!("dmesg {}", 42);
+    let mut out = [0u8; 8];
+    assert_eq!(read(&mut out), 8);
+    assert_eq!(&out, b"dmesg 42");
+
+    let before = written();
+    for _ in 0..DMESG_SIZE / 8 {
+        write(b"wrapping");
+    }
+    write(b"end");
+    assert_eq!(written(), before + DMESG_SIZE + 3);
+    let mut out = [0u8; 11];
+    assert_eq!(read(&mut out), 11);
+    assert_eq!(&out, b"wrappingend");
+}
@@ -0,0 +1,384 @@
+//! Loading statically linked ELF64 executables into user space.
+
+use crate::memory;
+use crate::user::{self, Exit, USER_SPACE_END, USER_SPACE_START};
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+/// The user stack ends right below the memory handed out by the `ALLOC`
+/// system call.
+pub const STACK_END: u64 = 0x0000_1800_0000_0000;
+pub const STACK_PAGES: u64 = 16;
+/// The part of the stack `argv` and `envp` leave to the program at least.
+pub const MIN_FREE_STACK: u64 = 4096;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    Unsupported,
+    BadSegment,
+    /// The entry point is not canonical or not in an executable segment.
+    BadEntry,
+    /// `argv` and `envp` do not fit on the stack with `MIN_FREE_STACK` to
+    /// spare.
+    ArgumentsTooLarge,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        ElfError::Map(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+/// A validated executable, borrowing the image it was parsed from.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: u64,
+    program_header_count: u16,
+}
+
+fn read<const N: usize>(data: &[u8], offset: u64) -> Result<[u8; N], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+    let bytes = data
+        .get(start..start.checked_add(N).ok_or(ElfError::Truncated)?)
+        .ok_or(ElfError::Truncated)?;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: u64) -> Result<u16, ElfError> {
+    read(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: u64) -> Result<u32, ElfError> {
+    read(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: u64) -> Result<u64, ElfError> {
+    read(data, offset).map(u64::from_le_bytes)
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64
+            || data[5] != DATA_LITTLE_ENDIAN
+            || read_u16(data, 16)? != TYPE_EXECUTABLE
+            || read_u16(data, 18)? != MACHINE_X86_64
+            || usize::from(read_u16(data, 54)?) != PROGRAM_HEADER_SIZE
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = ElfFile {
+            data,
+            entry: read_u64(data, 24)?,
+            program_headers: read_u64(data, 32)?,
+            program_header_count: read_u16(data, 56)?,
+        };
+        VirtAddr::try_new(elf.entry).map_err(|_| ElfError::BadEntry)?;
+        let mut entry_executable = false;
+        for header in elf.program_headers() {
+            let header = header?;
+            let in_segment = header.vaddr <= elf.entry
+                && elf.entry - header.vaddr < header.mem_size
+                && header.kind == PT_LOAD;
+            entry_executable |= in_segment && header.flags & PF_X != 0;
+        }
+        if !entry_executable {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> + '_ {
+        (0..u64::from(self.program_header_count)).map(move |i| {
+            let base = self.program_headers + i * PROGRAM_HEADER_SIZE as u64;
+            Ok(ProgramHeader {
+                kind: read_u32(self.data, base)?,
+                flags: read_u32(self.data, base + 4)?,
+                offset: read_u64(self.data, base + 8)?,
+                vaddr: read_u64(self.data, base + 16)?,
+                file_size: read_u64(self.data, base + 32)?,
+                mem_size: read_u64(self.data, base + 40)?,
+            })
+        })
+    }
+
+    fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(header.offset).map_err(|_| ElfError::BadSegment)?;
+        let len = usize::try_from(header.file_size).map_err(|_| ElfError::BadSegment)?;
+        let end = start.checked_add(len).ok_or(ElfError::BadSegment)?;
+        self.data.get(start..end).ok_or(ElfError::BadSegment)
+    }
+}
+
+/// A program mapped into user space, ready to run.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+impl LoadedProgram {
+    /// Jumps to the entry point in ring 3 and returns how the program ended.
+    ///
+    /// # Safety
+    ///
+    /// See `user::run`.
+    pub unsafe fn run(&self) -> Exit {
+        user::run(self.entry, self.stack_pointer)
+    }
+}
+
+/// Maps the `PT_LOAD` segments of `elf` and a stack holding `argv` and
+/// `envp` the way the System V ABI lays them out. If that fails, the pages
+/// of the segments and the stack are unmapped and freed again.
+pub fn load(
+    elf: &ElfFile,
+    argv: &[&str],
+    envp: &[&str],
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<LoadedProgram, ElfError> {
+    match arguments_size(argv, envp) {
+        Some(size) if size <= STACK_PAGES * 4096 - MIN_FREE_STACK => {}
+        _ => return Err(ElfError::ArgumentsTooLarge),
+    }
+    let mut ranges = Vec::new();
+    if let Err(error) = map_program(elf, mapper, frame_allocator, &mut ranges) {
+        for &(start, count) in ranges.iter() {
+            user::unmap_user_pages(start, count, mapper, frame_allocator);
+        }
+        return Err(error);
+    }
+    let stack_pointer = write_arguments(argv, envp, mapper);
+
+    Ok(LoadedProgram {
+        entry: elf.entry(),
+        stack_pointer,
+    })
+}
+
+// Maps the segments and the stack, adding the pages of each to `ranges`
+// before mapping them.
+fn map_program(
+    elf: &ElfFile,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ranges: &mut Vec<(Page, u64)>,
+) -> Result<(), ElfError> {
+    for header in elf.program_headers() {
+        let header = header?;
+        if header.kind == PT_LOAD {
+            load_segment(elf, &header, mapper, frame_allocator, ranges)?;
+        }
+    }
+
+    let stack_start = Page::containing_address(VirtAddr::new(STACK_END - STACK_PAGES * 4096));
+    ranges.push((stack_start, STACK_PAGES));
+    for page in Page::range(stack_start, stack_start + STACK_PAGES) {
+        let frame = new_frame(frame_allocator)?;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        map_frame(page, frame, flags, mapper, frame_allocator)?;
+    }
+    Ok(())
+}
+
+fn load_segment(
+    elf: &ElfFile,
+    header: &ProgramHeader,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ranges: &mut Vec<(Page, u64)>,
+) -> Result<(), ElfError> {
+    let data = elf.segment_data(header)?;
+    let end = header
+        .vaddr
+        .checked_add(header.mem_size)
+        .ok_or(ElfError::BadSegment)?;
+    if header.file_size > header.mem_size || header.vaddr < USER_SPACE_START || end > USER_SPACE_END
+    {
+        return Err(ElfError::BadSegment);
+    }
+    if header.mem_size == 0 {
+        return Ok(());
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if header.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if header.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(header.vaddr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    ranges.push((first, last - first + 1));
+    for page in Page::range_inclusive(first, last) {
+        // Segments may share a page at their edges.
+        let frame = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame,
+                flags: existing,
+                ..
+            } => {
+                let frame = PhysFrame::containing_address(frame.start_address());
+                let mut merged = existing | flags;
+                if !(existing & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe {
+                    mapper
+                        .update_flags(page, merged)
+                        .map_err(|_| ElfError::BadSegment)?
+                }
+                .flush();
+                frame
+            }
+            _ => {
+                let frame = new_frame(frame_allocator)?;
+                map_frame(page, frame, flags, mapper, frame_allocator)?;
+                frame
+            }
+        };
+
+        // Copy the part of the file backing this page through the physical
+        // memory mapping, since the page itself may be read-only.
+        let page_start = page.start_address().as_u64();
+        let copy_start = page_start.max(header.vaddr);
+        let copy_end = (page_start + 4096).min(header.vaddr + header.file_size);
+        if copy_start < copy_end {
+            let src =
+                &data[(copy_start - header.vaddr) as usize..(copy_end - header.vaddr) as usize];
+            let dst = memory::physical_memory_offset()
+                + frame.start_address().as_u64()
+                + (copy_start - page_start);
+            unsafe {
+                core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), src.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+// A zeroed frame, which also takes care of the `.bss` part of segments.
+fn new_frame(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<PhysFrame, ElfError> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let virt = memory::physical_memory_offset() + frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096) };
+    Ok(frame)
+}
+
+// Maps `frame` at `page`, or frees it if that fails.
+fn map_frame(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), ElfError> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let parent_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    unsafe {
+        match mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                frame_allocator.deallocate_frame(frame);
+                return Err(error.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// The bytes `write_arguments` takes at most, including alignment.
+fn arguments_size(argv: &[&str], envp: &[&str]) -> Option<u64> {
+    let strings = argv.iter().chain(envp).try_fold(0u64, |size, s| {
+        size.checked_add(u64::try_from(s.len()).ok()?.checked_add(1)?)
+    })?;
+    let words = u64::try_from(argv.len().checked_add(envp.len())?.checked_add(5)?).ok()?;
+    strings.checked_add(words.checked_mul(8)?)?.checked_add(15)
+}
+
+// Writes the strings to the top of the stack, followed downwards by the
+// auxiliary vector (empty), `envp`, `argv` and `argc`, and returns the
+// 16-byte aligned stack pointer pointing at `argc`. `load` made sure they
+// fit.
+fn write_arguments(argv: &[&str], envp: &[&str], mapper: &impl Translate) -> VirtAddr {
+    let mut top = STACK_END;
+    let mut push_str = |s: &str| {
+        top -= s.len() as u64 + 1;
+        write_user(mapper, top, s.as_bytes());
+        write_user(mapper, top + s.len() as u64, &[0]);
+        top
+    };
+    let argv_ptrs: Vec<u64> = argv.iter().map(|s| push_str(s)).collect();
+    let envp_ptrs: Vec<u64> = envp.iter().map(|s| push_str(s)).collect();
+
+    // argc, argv, NULL, envp, NULL, AT_NULL
+    let mut words = Vec::with_capacity(argv.len() + envp.len() + 5);
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.extend_from_slice(&[0, 0, 0]);
+
+    let sp = (top - words.len() as u64 * 8) & !0xf;
+    for (i, word) in words.iter().enumerate() {
+        write_user(mapper, sp + i as u64 * 8, &word.to_le_bytes());
+    }
+    VirtAddr::new(sp)
+}
+
+// Writes to a mapped user page through the physical memory mapping, so that
+// this works no matter which address space is active.
+fn write_user(mapper: &impl Translate, addr: u64, bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        let virt = VirtAddr::new(addr + i as u64);
+        let phys = mapper.translate_addr(virt).expect("user stack not mapped");
+        unsafe { *(memory::physical_memory_offset() + phys.as_u64()).as_mut_ptr::<u8>() = *byte };
+    }
+}
@@ -0,0 +1,265 @@
+//! Printing that cannot deadlock, whatever it interrupted.
+//!
+//! `print!` and `serial_print!` go through an `OutputLock`, which knows the
+//! CPU holding the lock of the writer, as does other code using the writer
+//! through `OutputLock::lock`. Output of an exception handler that
+//! interrupted its own CPU while it held the lock goes to an emergency
+//! buffer of that CPU instead, and is written out the next time the CPU
+//! gets the lock. Once `begin_panic` was called, the locks are released
+//! and output that would still have to wait for another CPU is written
+//! past the locks, straight to the hardware.
+
+use crate::smp::{self, MAX_CPUS};
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+/// Bytes of output each CPU can hold back; more is dropped, but still kept
+/// by `dmesg`.
+pub const EMERGENCY_BUFFER_SIZE: usize = 1024;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// No CPU holds the lock.
+const NO_OWNER: usize = usize::MAX;
+
+/// Whether a panic handler called `begin_panic`.
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// Called by panic handlers before they print: releases the locks of the
+/// VGA and serial writers, whoever holds them, and has printing bypass them
+/// rather than wait from then on.
+pub fn begin_panic() {
+    PANICKING.store(true, Ordering::SeqCst);
+    unsafe {
+        crate::vga_buffer::OUTPUT.force_unlock(&crate::vga_buffer::WRITER);
+        crate::serial::OUTPUT.force_unlock(&crate::serial::SERIAL1);
+    }
+}
+
+/// Releases the writer locks the current CPU holds, for code that abandons
+/// a call stack in the middle of printing, like a panic recovered from.
+pub fn release_current_cpu() {
+    unsafe {
+        crate::vga_buffer::OUTPUT.release_current_cpu(&crate::vga_buffer::WRITER);
+        crate::serial::OUTPUT.release_current_cpu(&crate::serial::SERIAL1);
+    }
+}
+
+struct EmergencyBuffer {
+    bytes: [AtomicU8; EMERGENCY_BUFFER_SIZE],
+    len: AtomicUsize,
+}
+
+impl EmergencyBuffer {
+    const fn new() -> EmergencyBuffer {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU8 = AtomicU8::new(0);
+        EmergencyBuffer {
+            bytes: [ZERO; EMERGENCY_BUFFER_SIZE],
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    // Only called on the CPU the buffer belongs to, but maybe by a handler
+    // nested in another call.
+    fn write(&self, bytes: &[u8]) {
+        let start = self.len.fetch_add(bytes.len(), Ordering::AcqRel);
+        for (i, &byte) in bytes.iter().enumerate() {
+            if let Some(slot) = self.bytes.get(start + i) {
+                slot.store(byte, Ordering::Release);
+            }
+        }
+    }
+
+    fn flush(&self, out: &mut impl fmt::Write) {
+        let len = self.len.load(Ordering::Acquire).min(EMERGENCY_BUFFER_SIZE);
+        let mut bytes = [0; EMERGENCY_BUFFER_SIZE];
+        for (byte, slot) in bytes[..len].iter_mut().zip(self.bytes.iter()) {
+            *byte = slot.load(Ordering::Acquire);
+        }
+        for chunk in bytes[..len].utf8_chunks() {
+            let _ = out.write_str(chunk.valid());
+        }
+        self.len.store(0, Ordering::Release);
+    }
+}
+
+impl fmt::Write for &EmergencyBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Keeps track of who holds the lock of a writer, and the output held back
+/// from it on each CPU.
+pub struct OutputLock {
+    owner: AtomicUsize,
+    deferred: [EmergencyBuffer; MAX_CPUS],
+}
+
+impl OutputLock {
+    pub const fn new() -> OutputLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: EmergencyBuffer = EmergencyBuffer::new();
+        OutputLock {
+            owner: AtomicUsize::new(NO_OWNER),
+            deferred: [EMPTY; MAX_CPUS],
+        }
+    }
+
+    /// Writes `args` to what `writer` protects, first writing what this CPU
+    /// held back. If the lock is held by this CPU, which means this was
+    /// called from an exception handler, `args` is held back instead; if it
+    /// is held by another one after `begin_panic`, `args` goes to `fallback`.
+    /// Must be called with interrupts disabled.
+    pub fn write<T: fmt::Write>(
+        &self,
+        writer: &Mutex<T>,
+        args: fmt::Arguments,
+        fallback: impl FnOnce(fmt::Arguments),
+    ) {
+        let cpu = smp::cpu_index();
+        loop {
+            match self.claim(writer, cpu) {
+                Ok(mut guard) => {
+                    let _ = guard.write_fmt(args);
+                    self.release(guard);
+                    return;
+                }
+                Err(owner) if owner == cpu => {
+                    let _ = fmt::write(&mut &self.deferred[cpu], args);
+                    return;
+                }
+                Err(_) => {}
+            }
+            if panicking() {
+                fallback(args);
+                return;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Runs `f` with what `writer` protects, for uses other than printing,
+    /// which must not lock `writer` themselves so that output of exception
+    /// handlers interrupting them is held back as for `write`. Must be
+    /// called with interrupts disabled.
+    pub fn lock<T: fmt::Write, R>(&self, writer: &Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R {
+        let cpu = smp::cpu_index();
+        loop {
+            if let Ok(mut guard) = self.claim(writer, cpu) {
+                let result = f(&mut guard);
+                self.release(guard);
+                return result;
+            }
+            spin_loop();
+        }
+    }
+
+    // Takes the lock of `writer` for `cpu` and writes out what it held back,
+    // or returns the owner in the way. The owner is claimed before the lock
+    // is taken, so a handler interrupting this CPU while it holds the lock
+    // always finds it the owner.
+    fn claim<'a, T: fmt::Write>(
+        &self,
+        writer: &'a Mutex<T>,
+        cpu: usize,
+    ) -> Result<MutexGuard<'a, T>, usize> {
+        self.owner
+            .compare_exchange(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)?;
+        match writer.try_lock() {
+            Some(mut guard) => {
+                self.deferred[cpu].flush(&mut *guard);
+                Ok(guard)
+            }
+            // Locked by someone not going through here
+            None => {
+                self.owner.store(NO_OWNER, Ordering::Release);
+                Err(NO_OWNER)
+            }
+        }
+    }
+
+    // Gives up the lock before the owner, for the same reason.
+    fn release<T>(&self, guard: MutexGuard<T>) {
+        drop(guard);
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+
+    /// Whether output of this CPU is held back.
+    pub fn has_deferred(&self) -> bool {
+        self.deferred[smp::cpu_index()].len.load(Ordering::Acquire) > 0
+    }
+
+    /// # Safety
+    ///
+    /// Whoever holds `writer` must not use it anymore.
+    pub unsafe fn force_unlock<T>(&self, writer: &Mutex<T>) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        writer.force_unlock();
+    }
+
+    /// # Safety
+    ///
+    /// The code on this CPU holding `writer`, if any, must never resume.
+    pub unsafe fn release_current_cpu<T>(&self, writer: &Mutex<T>) {
+        if self.owner.load(Ordering::Relaxed) == smp::cpu_index() {
+            self.force_unlock(writer);
+        }
+    }
+}
+
+impl Default for OutputLock {
+    fn default() -> Self {
+        OutputLock::new()
+    }
+}
+
+#[test_case]
+fn test_nested_output_is_deferred() {
+    use core::fmt::Write;
+
+    struct Collected {
+        bytes: [u8; 64],
+        len: usize,
+    }
+
+    impl fmt::Write for Collected {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.bytes[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+            Ok(())
+        }
+    }
+
+    static WRITER: Mutex<Collected> = Mutex::new(Collected {
+        bytes: [0; 64],
+        len: 0,
+    });
+    static OUTPUT: OutputLock = OutputLock::new();
+
+    OUTPUT.write(&WRITER, format_args!("first "), |_| unreachable!());
+    {
+        // As if an exception hit while the lock was held
+        let mut writer = WRITER.lock();
+        OUTPUT.owner.store(smp::cpu_index(), Ordering::Relaxed);
+        OUTPUT.write(&WRITER, format_args!("nested {} ", 1), |_| unreachable!());
+        assert!(OUTPUT.has_deferred());
+        OUTPUT.owner.store(NO_OWNER, Ordering::Relaxed);
+        writer.write_str("held ").unwrap();
+    }
+    OUTPUT.write(&WRITER, format_args!("last"), |_| unreachable!());
+    assert!(!OUTPUT.has_deferred());
+    let writer = WRITER.lock();
+    assert_eq!(&writer.bytes[..writer.len], b"first held nested 1 last");
+
+    OUTPUT.owner.store(smp::cpu_index(), Ordering::Relaxed);
+    core::mem::forget(writer);
+    unsafe { OUTPUT.release_current_cpu(&WRITER) };
+    assert!(WRITER.try_lock().is_some());
+}
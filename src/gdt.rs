@@ -1,52 +1,127 @@
+use crate::smp;
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicPtr, Ordering};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const STACK_SIZE: usize = 4096 * 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+
+// The TSS loaded on each CPU. They are written to through these pointers
+// whenever the kernel stack for interrupts from user mode changes.
+static TSS: [AtomicPtr<TaskStateSegment>; smp::MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<TaskStateSegment> = AtomicPtr::new(core::ptr::null_mut());
+    [NONE; smp::MAX_CPUS]
+};
+
+fn new_tss(double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
+
+// The user segments follow the kernel data segment in the order `sysret`
+// expects them.
+fn new_gdt(tss: *mut TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(tss) });
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+        let tss = addr_of_mut!(BSP_TSS);
+        unsafe { tss.write(new_tss(stack_start + STACK_SIZE)) };
+        new_gdt(tss)
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Segment selectors, the same on every CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    load(&GDT);
+    TSS[0].store(addr_of_mut!(BSP_TSS), Ordering::Release);
+}
+
+/// Loads a GDT and TSS of its own on an application processor, whose
+/// double fault stack ends at `double_fault_stack_end`. A TSS is marked busy
+/// once loaded, so CPUs cannot share one; the tables are leaked since CPUs
+/// never go offline.
+pub fn init_ap(double_fault_stack_end: VirtAddr) {
+    let tss: *mut TaskStateSegment = Box::leak(Box::new(new_tss(double_fault_stack_end)));
+    load(Box::leak(Box::new(new_gdt(tss))));
+    TSS[smp::cpu_index()].store(tss, Ordering::Release);
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code_selector);
+        SS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+pub(crate) fn tss_address() -> VirtAddr {
+    VirtAddr::from_ptr(TSS[smp::cpu_index()].load(Ordering::Acquire))
+}
+
+/// The stack the CPU switches to on interrupts from user mode (RSP0).
+pub fn kernel_stack() -> VirtAddr {
+    let tss = TSS[smp::cpu_index()].load(Ordering::Acquire);
+    if tss.is_null() {
+        return VirtAddr::zero();
+    }
+    // The TSS is packed, so its fields may be unaligned.
+    unsafe { addr_of!((*tss).privilege_stack_table).read_unaligned()[0] }
+}
+
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    let tss = TSS[smp::cpu_index()].load(Ordering::Acquire);
+    if tss.is_null() {
+        return;
+    }
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        let table = addr_of_mut!((*tss).privilege_stack_table);
+        let mut stacks = table.read_unaligned();
+        stacks[0] = stack_end;
+        table.write_unaligned(stacks);
     }
 }
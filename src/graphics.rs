@@ -0,0 +1,417 @@
+//! VGA mode 13h: 320×200 pixels with a palette of 256 colours.
+//!
+//! `enter_mode_13h` reprograms the card and takes the screen from the text
+//! consoles, which keep writing to their backing buffers; `leave_mode_13h`
+//! gives it back. The first 16 colours are those of the text mode, followed
+//! by a 6×6×6 colour cube (see `rgb`) and a grey ramp. While in mode 13h,
+//! `print!` output is also drawn on the screen with the font of the text
+//! mode.
+//!
+//! Video memory is reached through the physical memory mapping, so
+//! `memory::init` must have been called.
+
+use crate::{memory, vga_buffer};
+use core::convert::TryFrom;
+use core::fmt;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use registers::Registers;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod registers;
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 200;
+
+/// Scan lines of a glyph of the text mode font.
+pub const GLYPH_HEIGHT: usize = 16;
+pub const GLYPH_WIDTH: usize = 8;
+
+const VIDEO_MEMORY: u64 = 0xa0000;
+
+// The registers of the text mode to go back to, and what `print!` output is
+// drawn with, while in mode 13h. The font and palette of the text mode are
+// kept in the statics below; the font is also what text is drawn with.
+static MODE_13H: Mutex<Option<(Registers, TextRenderer)>> = Mutex::new(None);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static mut FONT: [u8; registers::FONT_SIZE] = [0; registers::FONT_SIZE];
+static mut TEXT_PALETTE: [u8; registers::PALETTE_SIZE] = [0; registers::PALETTE_SIZE];
+
+fn video_memory() -> *mut u8 {
+    (memory::physical_memory_offset() + VIDEO_MEMORY).as_mut_ptr()
+}
+
+// The screen as a canvas; only one may exist at a time.
+unsafe fn screen() -> Canvas<'static> {
+    Canvas::new(
+        core::slice::from_raw_parts_mut(video_memory(), WIDTH * HEIGHT),
+        WIDTH,
+    )
+}
+
+/// The glyph of `byte` in code page 437, one byte per scan line with the
+/// leftmost pixel in the highest bit. Blank until mode 13h was entered once.
+pub fn glyph(byte: u8) -> &'static [u8] {
+    let start = usize::from(byte) * 32;
+    unsafe { &(&*addr_of!(FONT))[start..start + GLYPH_HEIGHT] }
+}
+
+/// The colour closest to `red`, `green` and `blue` in the colour cube.
+pub fn rgb(red: u8, green: u8, blue: u8) -> u8 {
+    let level = |value: u8| (u16::from(value) * 5 + 127) / 255;
+    (16 + level(red) * 36 + level(green) * 6 + level(blue)) as u8
+}
+
+fn default_palette() -> [u8; registers::PALETTE_SIZE] {
+    const TEXT_COLORS: [[u8; 3]; 16] = [
+        [0, 0, 0],
+        [0, 0, 42],
+        [0, 42, 0],
+        [0, 42, 42],
+        [42, 0, 0],
+        [42, 0, 42],
+        [42, 21, 0],
+        [42, 42, 42],
+        [21, 21, 21],
+        [21, 21, 63],
+        [21, 63, 21],
+        [21, 63, 63],
+        [63, 21, 21],
+        [63, 21, 63],
+        [63, 63, 21],
+        [63, 63, 63],
+    ];
+
+    let mut palette = [0; registers::PALETTE_SIZE];
+    for (color, rgb) in palette.chunks_mut(3).enumerate() {
+        match color {
+            0..=15 => rgb.copy_from_slice(&TEXT_COLORS[color]),
+            16..=231 => {
+                let cube = color - 16;
+                let level = |i: usize| (i * 63 / 5) as u8;
+                rgb.copy_from_slice(&[level(cube / 36), level(cube / 6 % 6), level(cube % 6)]);
+            }
+            _ => rgb.fill(((color - 231) * 63 / 25) as u8),
+        }
+    }
+    palette
+}
+
+/// Whether the screen is in mode 13h.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Switches the screen to mode 13h, cleared to black. Does nothing if it
+/// already is.
+pub fn enter_mode_13h() {
+    interrupts::without_interrupts(|| {
+        let mut mode = MODE_13H.lock();
+        if mode.is_some() {
+            return;
+        }
+        vga_buffer::detach_screen();
+        let registers = Registers::read();
+        unsafe {
+            registers::read_font(&mut *addr_of_mut!(FONT));
+            registers::read_palette(&mut *addr_of_mut!(TEXT_PALETTE));
+            registers::MODE_13H.write();
+            screen().fill(0);
+        }
+        registers::write_palette(0, &default_palette());
+        *mode = Some((registers, TextRenderer::new(7, 0)));
+        ACTIVE.store(true, Ordering::Relaxed);
+    });
+}
+
+/// Goes back to the text mode `enter_mode_13h` left, showing the active
+/// console again.
+pub fn leave_mode_13h() {
+    interrupts::without_interrupts(|| {
+        let mut mode = MODE_13H.lock();
+        let (registers, _) = match mode.take() {
+            Some(mode) => mode,
+            None => return,
+        };
+        ACTIVE.store(false, Ordering::Relaxed);
+        unsafe {
+            registers.write();
+            registers::write_font(&*addr_of!(FONT));
+            registers::write_palette(0, &*addr_of!(TEXT_PALETTE));
+        }
+        vga_buffer::attach_screen();
+    });
+}
+
+/// Runs `f` with the screen, or returns `None` if it is not in mode 13h.
+pub fn with_screen<R>(f: impl FnOnce(&mut Canvas) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mode = MODE_13H.lock();
+        mode.as_ref()?;
+        Some(f(&mut unsafe { screen() }))
+    })
+}
+
+/// Sets colour `index` to 8 bit `red`, `green` and `blue` values, of which
+/// the card keeps 6 bits.
+pub fn set_color(index: u8, red: u8, green: u8, blue: u8) {
+    interrupts::without_interrupts(|| {
+        let _mode = MODE_13H.lock();
+        registers::write_palette(index, &[red >> 2, green >> 2, blue >> 2]);
+    });
+}
+
+// Output printed while the screen is being drawn on, maybe by the code
+// printing, only goes to the text console.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if !is_active() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        if let Some((_, text)) = MODE_13H.try_lock().as_mut().and_then(|mode| mode.as_mut()) {
+            text.write_fmt(&mut unsafe { screen() }, args);
+        }
+    });
+}
+
+/// Pixels of one byte each, row after row.
+pub struct Canvas<'a> {
+    pixels: &'a mut [u8],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Canvas<'a> {
+    /// A canvas `width` pixels wide drawing into `pixels`.
+    pub fn new(pixels: &'a mut [u8], width: usize) -> Canvas<'a> {
+        // No pixels fit in a canvas with no width.
+        let height = pixels.len().checked_div(width).unwrap_or(0);
+        Canvas {
+            pixels,
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        let (x, y) = (usize::try_from(x).ok()?, usize::try_from(y).ok()?);
+        (x < self.width && y < self.height).then(|| y * self.width + x)
+    }
+
+    /// The colour at `x`, `y`, if it is on the canvas.
+    pub fn pixel(&self, x: i32, y: i32) -> Option<u8> {
+        Some(self.pixels[self.index(x, y)?])
+    }
+
+    /// Sets the pixel at `x`, `y`; pixels off the canvas are left out, as
+    /// by all drawing functions.
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: u8) {
+        if let Some(i) = self.index(x, y) {
+            self.pixels[i] = color;
+        }
+    }
+
+    pub fn fill(&mut self, color: u8) {
+        self.pixels.fill(color);
+    }
+
+    /// Draws a line from `from` to `to`, both ends included.
+    pub fn line(&mut self, from: (i32, i32), to: (i32, i32), color: u8) {
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (step_x, step_y) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut error = dx + dy;
+        loop {
+            self.set_pixel(x, y, color);
+            if (x, y) == to {
+                break;
+            }
+            if 2 * error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if 2 * error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of the rectangle `width` by `height` pixels with
+    /// its top left corner at `x`, `y`.
+    pub fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u8) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        self.line((x, y), (right, y), color);
+        self.line((x, bottom), (right, bottom), color);
+        self.line((x, y), (x, bottom), color);
+        self.line((right, y), (right, bottom), color);
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u8) {
+        let columns = self.clip(x, width, self.width);
+        for row in self.clip(y, height, self.height) {
+            let start = row * self.width;
+            self.pixels[start + columns.start..start + columns.end].fill(color);
+        }
+    }
+
+    // The part of `start..start + len` within `0..limit`.
+    fn clip(&self, start: i32, len: i32, limit: usize) -> core::ops::Range<usize> {
+        let clamp = |n: i32| n.clamp(0, limit as i32) as usize;
+        clamp(start)..clamp(start.saturating_add(len.max(0)))
+    }
+
+    /// Copies `pixels`, an image `width` pixels wide, with its top left
+    /// corner to `x`, `y`.
+    pub fn blit(&mut self, x: i32, y: i32, pixels: &[u8], width: usize) {
+        let height = pixels.len().checked_div(width).unwrap_or(0);
+        let columns = self.clip(x, width as i32, self.width);
+        for row in self.clip(y, height as i32, self.height) {
+            let source = (row as i32 - y) as usize * width + (columns.start as i32 - x) as usize;
+            let target = row * self.width + columns.start;
+            self.pixels[target..target + columns.len()]
+                .copy_from_slice(&pixels[source..source + columns.len()]);
+        }
+    }
+
+    /// Draws `glyph`, a bitmap 8 pixels wide, with its top left corner at
+    /// `x`, `y`.
+    pub fn draw_glyph(&mut self, x: i32, y: i32, glyph: &[u8], foreground: u8, background: u8) {
+        for (row, &bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                let set = bits & (0x80 >> column) != 0;
+                let color = if set { foreground } else { background };
+                self.set_pixel(x + column as i32, y + row as i32, color);
+            }
+        }
+    }
+
+    /// Moves the contents up by `rows`, filling the rows freed at the bottom
+    /// with `color`.
+    pub fn scroll_up(&mut self, rows: usize, color: u8) {
+        let rows = rows.min(self.height);
+        let len = self.width * self.height;
+        self.pixels.copy_within(rows * self.width..len, 0);
+        self.pixels[len - rows * self.width..len].fill(color);
+    }
+}
+
+// Where `TextRenderer` is in an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    // After ESC.
+    Started,
+    // After ESC [, up to the final byte.
+    Csi,
+}
+
+/// Draws text in a grid of glyphs of the text mode font, scrolling when it
+/// reaches the bottom. Escape sequences are skipped.
+pub struct TextRenderer {
+    column: usize,
+    row: usize,
+    foreground: u8,
+    background: u8,
+    escape: Escape,
+}
+
+impl TextRenderer {
+    pub const fn new(foreground: u8, background: u8) -> TextRenderer {
+        TextRenderer {
+            column: 0,
+            row: 0,
+            foreground,
+            background,
+            escape: Escape::None,
+        }
+    }
+
+    pub fn set_colors(&mut self, foreground: u8, background: u8) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// The column and row the next glyph goes to.
+    pub fn position(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    pub fn write_str(&mut self, canvas: &mut Canvas, s: &str) {
+        for c in s.chars() {
+            match self.escape {
+                Escape::None => {}
+                Escape::Started => {
+                    self.escape = if c == '[' { Escape::Csi } else { Escape::None };
+                    continue;
+                }
+                Escape::Csi => {
+                    if matches!(c, '@'..='~') {
+                        self.escape = Escape::None;
+                    }
+                    continue;
+                }
+            }
+            match c {
+                '\x1b' => self.escape = Escape::Started,
+                '\n' => self.new_line(canvas),
+                '\r' => self.column = 0,
+                c if c.is_ascii() && !c.is_ascii_control() => self.put(canvas, c as u8),
+                c => self.put(canvas, vga_buffer::to_cp437(c).unwrap_or(0xfe)),
+            }
+        }
+    }
+
+    pub fn write_fmt(&mut self, canvas: &mut Canvas, args: fmt::Arguments) {
+        struct Adapter<'r, 'c, 'p> {
+            text: &'r mut TextRenderer,
+            canvas: &'c mut Canvas<'p>,
+        }
+
+        impl fmt::Write for Adapter<'_, '_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.text.write_str(self.canvas, s);
+                Ok(())
+            }
+        }
+
+        let _ = fmt::write(&mut Adapter { text: self, canvas }, args);
+    }
+
+    fn put(&mut self, canvas: &mut Canvas, byte: u8) {
+        if self.column >= canvas.width() / GLYPH_WIDTH {
+            self.new_line(canvas);
+        }
+        let (x, y) = (self.column * GLYPH_WIDTH, self.row * GLYPH_HEIGHT);
+        canvas.draw_glyph(
+            x as i32,
+            y as i32,
+            glyph(byte),
+            self.foreground,
+            self.background,
+        );
+        self.column += 1;
+    }
+
+    fn new_line(&mut self, canvas: &mut Canvas) {
+        self.column = 0;
+        if self.row + 1 < canvas.height() / GLYPH_HEIGHT {
+            self.row += 1;
+        } else {
+            canvas.scroll_up(GLYPH_HEIGHT, self.background);
+        }
+    }
+}
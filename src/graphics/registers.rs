@@ -0,0 +1,214 @@
+//! The VGA registers setting up a display mode, and access to the font and
+//! palette memory behind them.
+
+use x86_64::instructions::port::Port;
+
+const MISC_WRITE: u16 = 0x3c2;
+const MISC_READ: u16 = 0x3cc;
+const SEQUENCER_INDEX: u16 = 0x3c4;
+const SEQUENCER_DATA: u16 = 0x3c5;
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const GRAPHICS_INDEX: u16 = 0x3ce;
+const GRAPHICS_DATA: u16 = 0x3cf;
+const ATTRIBUTE_INDEX: u16 = 0x3c0;
+const ATTRIBUTE_READ: u16 = 0x3c1;
+// Reading it resets the attribute controller to expect an index.
+const INPUT_STATUS: u16 = 0x3da;
+const DAC_READ_INDEX: u16 = 0x3c7;
+const DAC_WRITE_INDEX: u16 = 0x3c8;
+const DAC_DATA: u16 = 0x3c9;
+
+// Set with the attribute index to have the palette drive the display again.
+const PALETTE_ADDRESS_SOURCE: u8 = 0x20;
+
+// The CRTC registers below 8 are locked while this bit in register 0x11 is
+// set.
+const CRTC_PROTECT: u8 = 0x80;
+
+/// Bytes of the palette, a red, green and blue value of 6 bits per colour.
+pub const PALETTE_SIZE: usize = 256 * 3;
+
+/// Bytes of the font in plane 2: 256 glyphs of 32 scan lines.
+pub const FONT_SIZE: usize = 256 * 32;
+
+/// The registers making up a display mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registers {
+    misc: u8,
+    sequencer: [u8; 5],
+    crtc: [u8; 25],
+    graphics: [u8; 9],
+    attribute: [u8; 21],
+}
+
+/// 320×200 with 256 colours, one byte per pixel from 0xa0000.
+pub const MODE_13H: Registers = Registers {
+    misc: 0x63,
+    sequencer: [0x03, 0x01, 0x0f, 0x00, 0x0e],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0xbf, 0x1f, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x9c, 0x0e, 0x8f, 0x28, 0x40, 0x96, 0xb9, 0xa3, 0xff,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0f, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x41, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+fn read_indexed(index_port: u16, data_port: u16, index: u8) -> u8 {
+    unsafe {
+        Port::new(index_port).write(index);
+        Port::new(data_port).read()
+    }
+}
+
+fn write_indexed(index_port: u16, data_port: u16, index: u8, value: u8) {
+    unsafe {
+        Port::new(index_port).write(index);
+        Port::new(data_port).write(value);
+    }
+}
+
+fn reset_attribute_flip_flop() {
+    unsafe { Port::<u8>::new(INPUT_STATUS).read() };
+}
+
+impl Registers {
+    /// The mode the card is in.
+    pub fn read() -> Registers {
+        let mut registers = Registers {
+            misc: unsafe { Port::new(MISC_READ).read() },
+            sequencer: [0; 5],
+            crtc: [0; 25],
+            graphics: [0; 9],
+            attribute: [0; 21],
+        };
+        for (i, value) in registers.sequencer.iter_mut().enumerate() {
+            *value = read_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, i as u8);
+        }
+        for (i, value) in registers.crtc.iter_mut().enumerate() {
+            *value = read_indexed(CRTC_INDEX, CRTC_DATA, i as u8);
+        }
+        for (i, value) in registers.graphics.iter_mut().enumerate() {
+            *value = read_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, i as u8);
+        }
+        for (i, value) in registers.attribute.iter_mut().enumerate() {
+            reset_attribute_flip_flop();
+            *value = read_indexed(ATTRIBUTE_INDEX, ATTRIBUTE_READ, i as u8);
+        }
+        reset_attribute_flip_flop();
+        unsafe { Port::new(ATTRIBUTE_INDEX).write(PALETTE_ADDRESS_SOURCE) };
+        registers
+    }
+
+    /// Switches the card to this mode. The contents of video memory are left
+    /// as they are.
+    ///
+    /// # Safety
+    ///
+    /// Code still using the memory layout of the previous mode, like the text
+    /// writers, must not touch video memory anymore.
+    pub unsafe fn write(&self) {
+        Port::new(MISC_WRITE).write(self.misc);
+        for (i, &value) in self.sequencer.iter().enumerate() {
+            write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, i as u8, value);
+        }
+        let protect = read_indexed(CRTC_INDEX, CRTC_DATA, 0x11);
+        write_indexed(CRTC_INDEX, CRTC_DATA, 0x11, protect & !CRTC_PROTECT);
+        for (i, &value) in self.crtc.iter().enumerate() {
+            let value = if i == 0x11 {
+                value & !CRTC_PROTECT
+            } else {
+                value
+            };
+            write_indexed(CRTC_INDEX, CRTC_DATA, i as u8, value);
+        }
+        write_indexed(CRTC_INDEX, CRTC_DATA, 0x11, self.crtc[0x11]);
+        for (i, &value) in self.graphics.iter().enumerate() {
+            write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, i as u8, value);
+        }
+        let mut attribute = Port::new(ATTRIBUTE_INDEX);
+        for (i, &value) in self.attribute.iter().enumerate() {
+            reset_attribute_flip_flop();
+            attribute.write(i as u8);
+            attribute.write(value);
+        }
+        reset_attribute_flip_flop();
+        attribute.write(PALETTE_ADDRESS_SOURCE);
+    }
+}
+
+// Maps plane 2, where text modes keep the font, alone at 0xa0000 while `f`
+// runs.
+unsafe fn with_font_plane<R>(f: impl FnOnce(*mut u8) -> R) -> R {
+    let map_mask = read_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, 0x02);
+    let memory_mode = read_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, 0x04);
+    let read_map = read_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x04);
+    let graphics_mode = read_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x05);
+    let miscellaneous = read_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x06);
+
+    write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, 0x02, 0x04);
+    write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, 0x04, 0x06);
+    write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x04, 0x02);
+    write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x05, 0x00);
+    write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x06, 0x04);
+    let result = f(super::video_memory());
+
+    write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, 0x02, map_mask);
+    write_indexed(SEQUENCER_INDEX, SEQUENCER_DATA, 0x04, memory_mode);
+    write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x04, read_map);
+    write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x05, graphics_mode);
+    write_indexed(GRAPHICS_INDEX, GRAPHICS_DATA, 0x06, miscellaneous);
+    result
+}
+
+/// Copies the font of the current text mode into `font`.
+///
+/// # Safety
+///
+/// The card must be in a text mode and nothing else may use video memory
+/// meanwhile.
+pub unsafe fn read_font(font: &mut [u8; FONT_SIZE]) {
+    with_font_plane(|plane| {
+        for (i, byte) in font.iter_mut().enumerate() {
+            *byte = plane.add(i).read_volatile();
+        }
+    })
+}
+
+/// Puts `font` back into plane 2, where mode 13h overwrote it.
+///
+/// # Safety
+///
+/// See `read_font`.
+pub unsafe fn write_font(font: &[u8; FONT_SIZE]) {
+    with_font_plane(|plane| {
+        for (i, &byte) in font.iter().enumerate() {
+            plane.add(i).write_volatile(byte);
+        }
+    })
+}
+
+pub fn read_palette(palette: &mut [u8; PALETTE_SIZE]) {
+    unsafe {
+        Port::new(DAC_READ_INDEX).write(0u8);
+        let mut data = Port::new(DAC_DATA);
+        for value in palette.iter_mut() {
+            *value = data.read();
+        }
+    }
+}
+
+/// Sets the colours from `first` on to the 6 bit red, green and blue values
+/// in `palette`.
+pub fn write_palette(first: u8, palette: &[u8]) {
+    unsafe {
+        Port::new(DAC_WRITE_INDEX).write(first);
+        let mut data = Port::new(DAC_DATA);
+        for &value in palette {
+            data.write(value);
+        }
+    }
+}
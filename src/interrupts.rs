@@ -1,6 +1,12 @@
-use crate::{gdt, print, println};
+use crate::user::{self, Exit};
+use crate::{address_space, apic, gdt, print, println, smp};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 use pic8259::ChainedPics;
 use spin;
@@ -8,19 +14,79 @@ use spin;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Inter-processor interrupt used to wake a halted CPU.
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+// Number of times each vector fired; spurious PIC interrupts are counted
+// separately since they never reached a device handler.
+static INTERRUPT_COUNTS: [AtomicU64; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// Hardware interrupt handlers running on each CPU, counting nested ones.
+static HANDLER_DEPTH: [AtomicUsize; smp::MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; smp::MAX_CPUS]
+};
+
+// Marks the current CPU as running a hardware interrupt handler while it
+// lives.
+struct HandlerGuard(usize);
+
+impl HandlerGuard {
+    fn enter() -> HandlerGuard {
+        let cpu = smp::cpu_index();
+        HANDLER_DEPTH[cpu].fetch_add(1, Ordering::Relaxed);
+        HandlerGuard(cpu)
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        HANDLER_DEPTH[self.0].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the current CPU is handling a hardware interrupt, rather than
+/// running whatever it interrupted. Exceptions do not count: they belong to
+/// the code that raised them.
+pub fn in_interrupt_handler() -> bool {
+    HANDLER_DEPTH[smp::cpu_index()].load(Ordering::Relaxed) > 0
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_exception_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
             idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_exception_handler);
             idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+            idt[InterruptIndex::SpuriousMaster.as_usize()].set_handler_fn(spurious_master_handler);
+            idt[InterruptIndex::SpuriousSlave.as_usize()].set_handler_fn(spurious_slave_handler);
+            idt[usize::from(WAKEUP_VECTOR)].set_handler_fn(wakeup_handler);
+            idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
+            idt[usize::from(user::EXIT_VECTOR)]
+                .set_handler_addr(user::exit_entry())
+                .set_privilege_level(PrivilegeLevel::Ring3);
         }
         idt
     };
@@ -30,24 +96,144 @@ pub fn init_idt() {
     IDT.load();
 }
 
+fn record(vector: u8) {
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+pub fn spurious_count() -> u64 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
+fn vector_name(vector: u8) -> Option<&'static str> {
+    match vector {
+        3 => Some("breakpoint"),
+        6 => Some("invalid opcode"),
+        8 => Some("double fault"),
+        13 => Some("general protection"),
+        14 => Some("page fault"),
+        v if v == InterruptIndex::Timer.as_u8() => Some("timer"),
+        v if v == InterruptIndex::Keyboard.as_u8() => Some("keyboard"),
+        v if v == InterruptIndex::SpuriousMaster.as_u8() => Some("irq7"),
+        v if v == InterruptIndex::SpuriousSlave.as_u8() => Some("irq15"),
+        WAKEUP_VECTOR => Some("wakeup ipi"),
+        apic::SPURIOUS_VECTOR => Some("apic spurious"),
+        _ => None,
+    }
+}
+
+/// Writes one line per known or triggered vector, in the spirit of
+/// `/proc/interrupts`, followed by the spurious interrupt total.
+pub fn write_interrupt_table(out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "{:>4} {:>12}  NAME", "VEC", "COUNT")?;
+    for vector in 0..=u8::MAX {
+        let count = interrupt_count(vector);
+        let name = vector_name(vector);
+        if count > 0 || name.is_some() {
+            writeln!(out, "{:>4} {:>12}  {}", vector, count, name.unwrap_or("-"))?;
+        }
+    }
+    writeln!(out, "{:>4} {:>12}  spurious", "SPU", spurious_count())
+}
+
+pub fn print_interrupt_table() {
+    struct Table;
+
+    impl fmt::Display for Table {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write_interrupt_table(f)
+        }
+    }
+
+    print!("{}", Table);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(frame: InterruptStackFrame) {
+    record(3);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn double_exception_handler(_frame: InterruptStackFrame, _code: u64) -> ! {
+    record(8);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", _frame);
 }
 
+// Faults in user mode end the user code, faults in the kernel are fatal.
+fn from_user_mode(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment & 3 == 3
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(frame: InterruptStackFrame) {
+    record(6);
+    if from_user_mode(&frame) {
+        user::exit(Exit::InvalidOpcode);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(frame: InterruptStackFrame, code: u64) {
+    record(13);
+    if from_user_mode(&frame) {
+        user::exit(Exit::GeneralProtection(code));
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        code, frame
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, code: PageFaultErrorCode) {
+    record(14);
+    let address = Cr2::read();
+    let write_to_read_only =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if code.contains(write_to_read_only) && address_space::handle_write_fault(address) {
+        return;
+    }
+    if from_user_mode(&frame) {
+        user::exit(Exit::PageFault {
+            address: address.as_u64(),
+            error: code.bits(),
+        });
+    }
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:?} ({:?})\n{:#?}",
+        address, code, frame
+    );
+}
+
 #[test_case]
 fn test_breakpoint() {
     x86_64::instructions::interrupts::int3();
 }
 
+#[test_case]
+fn test_breakpoint_counted() {
+    let before = interrupt_count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupt_count(3), before + 1);
+}
+
+#[test_case]
+fn test_handler_depth() {
+    assert!(!in_interrupt_handler());
+    {
+        let _handler = HandlerGuard::enter();
+        assert!(in_interrupt_handler());
+    }
+    assert!(!in_interrupt_handler());
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    SpuriousMaster = PIC_1_OFFSET + 7,
+    SpuriousSlave = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
@@ -61,15 +247,22 @@ impl InterruptIndex {
 }
 
 extern "x86-interrupt" fn timer_exception_handler(_stack_frame: InterruptStackFrame) {
+    let handler = HandlerGuard::enter();
+    record(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
     // print!("."); # TODO: Uncomment
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // Acknowledged first, as the next thread may not return here for a while.
+    drop(handler);
+    crate::thread::preempt();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+    let _handler = HandlerGuard::enter();
+    record(InterruptIndex::Keyboard.as_u8());
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
@@ -80,3 +273,58 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
 }
+
+// The in-service register tells whether IRQ7/IRQ15 was actually raised by a
+// device or is a spurious interrupt caused by a line dropping before the ack.
+fn read_isr(command_port: u16) -> u8 {
+    let mut port: Port<u8> = Port::new(command_port);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
+    }
+}
+
+extern "x86-interrupt" fn spurious_master_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = HandlerGuard::enter();
+    if read_isr(PIC_1_COMMAND) & 0x80 == 0 {
+        // Spurious IRQ7: the master never set the ISR bit, so no EOI.
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    record(InterruptIndex::SpuriousMaster.as_u8());
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SpuriousMaster.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn spurious_slave_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = HandlerGuard::enter();
+    if read_isr(PIC_2_COMMAND) & 0x80 == 0 {
+        // Spurious IRQ15: the master still saw the cascade on IRQ2 and has to
+        // be acknowledged, the slave must not be.
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        let mut port: Port<u8> = Port::new(PIC_1_COMMAND);
+        unsafe { port.write(PIC_EOI) };
+        return;
+    }
+
+    record(InterruptIndex::SpuriousSlave.as_u8());
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SpuriousSlave.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = HandlerGuard::enter();
+    record(WAKEUP_VECTOR);
+    apic::end_of_interrupt();
+}
+
+// Spurious local APIC interrupts are not in service and take no EOI.
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    let _handler = HandlerGuard::enter();
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
@@ -7,23 +7,57 @@
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod address_space;
 pub mod allocator;
+pub mod apic;
 pub mod async_task;
+pub mod context;
+pub mod dmesg;
+pub mod elf;
+pub mod emergency;
 pub mod gdt;
+pub mod graphics;
 pub mod interrupts;
+pub mod log;
 pub mod memory;
 pub mod serial;
+pub mod smp;
+pub mod status_bar;
+pub mod syscall;
+pub mod thread;
+pub mod time;
+pub mod user;
 pub mod vga_buffer;
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
+use bootloader::BootInfo;
+use memory::BootInfoFrameAllocator;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
 extern crate alloc;
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
+    time::init();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
 }
+/// Initializes the kernel, paging and the heap for a test, returning the
+/// mapper and frame allocator. Must be called only once.
+pub fn test_boot(
+    boot_info: &'static BootInfo,
+) -> (OffsetPageTable<'static>, BootInfoFrameAllocator) {
+    init();
+    let mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    (mapper, frame_allocator)
+}
+
 pub trait Testable {
     fn run(&self) -> ();
 }
@@ -48,6 +82,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    emergency::begin_panic();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failed);
@@ -81,7 +116,7 @@ entry_point!(test_kernel);
 
 #[cfg(test)]
 fn test_kernel(boot_info: &'static BootInfo) -> ! {
-    init();
+    test_boot(boot_info);
     test_main();
     hlt();
 }
@@ -0,0 +1,556 @@
+//! Leveled logging with per-module filters, in place of plain `println!`.
+//!
+//! Records are made with `error!`, `warn!`, `info!`, `debug!` and `trace!`,
+//! stamped with the timer ticks since boot and handed to every sink whose
+//! level lets them through. `init` adds the VGA, serial and memory sinks and
+//! applies `BOOT_CONFIG`, which is taken from the `RUSTOS_LOG` environment
+//! variable at build time, like `info,rustos::smp=debug,sink:serial=trace`:
+//!
+//! - a level alone is the default for all modules;
+//! - `module=level` applies to the module and those below it, the longest
+//!   match winning;
+//! - `sink:name=level` sets the level of a sink.
+//!
+//! Levels are `off`, `error`, `warn`, `info`, `debug` and `trace`.
+//!
+//! The bootloader passes no kernel command line, so the configuration at
+//! boot cannot change without a rebuild; `configure` changes it at run time.
+
+use crate::{smp, time};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The configuration `init` applies, fixed when the kernel is built.
+pub const BOOT_CONFIG: &str = match option_env!("RUSTOS_LOG") {
+    Some(config) => config,
+    None => "info",
+};
+
+pub const MAX_SINKS: usize = 8;
+pub const MAX_MODULE_FILTERS: usize = 16;
+
+/// Bytes of formatted records the memory sink keeps.
+pub const MEMORY_LOG_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    // `None` for off.
+    fn parse(s: &str) -> Result<Option<Level>, ()> {
+        if s.eq_ignore_ascii_case("off") {
+            return Ok(None);
+        }
+        let level = Level::ALL
+            .iter()
+            .find(|l| l.as_str().eq_ignore_ascii_case(s));
+        level.map(|&l| Some(l)).ok_or(())
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+// Levels as stored in atomics, with 0 for off.
+fn level_to_u8(level: Option<Level>) -> u8 {
+    level.map_or(0, |l| l as u8)
+}
+
+/// A message with where and when it was logged.
+pub struct Record<'a> {
+    pub level: Level,
+    pub module: &'a str,
+    pub ticks: u64,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = self.ticks * 1000 / time::TICK_HZ;
+        write!(
+            f,
+            "[{:5}.{:03}] {:<5} {}: {}",
+            ms / 1000,
+            ms % 1000,
+            self.level,
+            self.module,
+            self.args
+        )
+    }
+}
+
+/// Where records go. Sinks are called with interrupts disabled and must not
+/// log themselves.
+pub trait Sink: Sync {
+    fn write(&self, record: &Record);
+}
+
+struct SinkSlot {
+    name: &'static str,
+    sink: &'static dyn Sink,
+    level: Option<Level>,
+}
+
+struct Filters {
+    default: Option<Level>,
+    modules: [Option<(&'static str, Option<Level>)>; MAX_MODULE_FILTERS],
+}
+
+impl Filters {
+    fn level(&self, module: &str) -> Option<Level> {
+        let matching = self.modules.iter().flatten().filter(|(prefix, _)| {
+            let rest = module.strip_prefix(prefix);
+            rest.is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        });
+        match matching.max_by_key(|(prefix, _)| prefix.len()) {
+            Some(&(_, level)) => level,
+            None => self.default,
+        }
+    }
+
+    fn most_verbose(&self) -> Option<Level> {
+        let modules = self.modules.iter().flatten().map(|&(_, level)| level);
+        modules.fold(self.default, core::cmp::max)
+    }
+}
+
+const NO_SINK: Option<SinkSlot> = None;
+
+static SINKS: Mutex<[Option<SinkSlot>; MAX_SINKS]> = Mutex::new([NO_SINK; MAX_SINKS]);
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default: Some(Level::Info),
+    modules: [None; MAX_MODULE_FILTERS],
+});
+// The most verbose level of any filter, to skip the others quickly.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+// Whether each CPU is in `try_with`.
+static HOLDING: [AtomicBool; smp::MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO: AtomicBool = AtomicBool::new(false);
+    [NO; smp::MAX_CPUS]
+};
+
+// Runs `f` with `mutex` locked, unless this CPU is in such a call already, as
+// when logging from an exception handler that interrupted it: the lock would
+// never become free then. Interrupts must be disabled.
+fn try_with<T, R>(mutex: &Mutex<T>, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+    let holding = &HOLDING[smp::cpu_index()];
+    if holding.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    let result = f(&mut mutex.lock());
+    holding.store(false, Ordering::Release);
+    Some(result)
+}
+
+// For changing the configuration, which exception handlers and sinks do not.
+fn with<T, R>(mutex: &Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    interrupts::without_interrupts(|| try_with(mutex, f))
+        .expect("log configuration changed while logging")
+}
+
+fn update_max_level(filters: &Filters) {
+    MAX_LEVEL.store(level_to_u8(filters.most_verbose()), Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    BadLevel(&'static str),
+    UnknownSink(&'static str),
+    TooManySinks,
+    TooManyFilters,
+}
+
+/// Adds a sink under `name`, passing it records up to `level`.
+pub fn add_sink(
+    name: &'static str,
+    sink: &'static dyn Sink,
+    level: Option<Level>,
+) -> Result<(), ConfigError> {
+    with(&SINKS, |sinks| {
+        let free = sinks.iter_mut().find(|slot| slot.is_none());
+        let slot = free.ok_or(ConfigError::TooManySinks)?;
+        *slot = Some(SinkSlot { name, sink, level });
+        Ok(())
+    })
+}
+
+/// Removes the sink added under `name`.
+pub fn remove_sink(name: &'static str) -> Result<(), ConfigError> {
+    with(&SINKS, |sinks| {
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(slot) if slot.name == name));
+        *slot.ok_or(ConfigError::UnknownSink(name))? = None;
+        Ok(())
+    })
+}
+
+pub fn set_sink_level(name: &'static str, level: Option<Level>) -> Result<(), ConfigError> {
+    with(&SINKS, |sinks| {
+        let slot = sinks.iter_mut().flatten().find(|slot| slot.name == name);
+        slot.ok_or(ConfigError::UnknownSink(name))?.level = level;
+        Ok(())
+    })
+}
+
+/// Sets the level of modules without a filter of their own.
+pub fn set_level(level: Option<Level>) {
+    with(&FILTERS, |filters| {
+        filters.default = level;
+        update_max_level(filters);
+    });
+}
+
+/// Sets the level of `module` and the modules below it.
+pub fn set_module_level(module: &'static str, level: Option<Level>) -> Result<(), ConfigError> {
+    with(&FILTERS, |filters| {
+        let existing = filters
+            .modules
+            .iter()
+            .position(|f| matches!(f, Some((m, _)) if *m == module));
+        let free = filters.modules.iter().position(|f| f.is_none());
+        let slot = existing.or(free).ok_or(ConfigError::TooManyFilters)?;
+        filters.modules[slot] = Some((module, level));
+        update_max_level(filters);
+        Ok(())
+    })
+}
+
+/// Drops all module filters and sets the default level back to `Info`, as
+/// before `init`.
+pub fn reset_filters() {
+    with(&FILTERS, |filters| {
+        filters.default = Some(Level::Info);
+        filters.modules = [None; MAX_MODULE_FILTERS];
+        update_max_level(filters);
+    });
+}
+
+/// Applies a configuration as described for `BOOT_CONFIG`. Directives before
+/// a bad one stay applied.
+pub fn configure(config: &'static str) -> Result<(), ConfigError> {
+    for directive in config.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (target, level) = match directive.rfind('=') {
+            Some(i) => (Some(&directive[..i]), &directive[i + 1..]),
+            None => (None, directive),
+        };
+        let level = Level::parse(level).map_err(|()| ConfigError::BadLevel(level))?;
+        match target {
+            None => set_level(level),
+            Some(target) => match target.strip_prefix("sink:") {
+                Some(sink) => set_sink_level(sink, level)?,
+                None => set_module_level(target, level)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Whether a record of `level` from `module` would be passed to the sinks.
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    // Without the filters, the most verbose one decides.
+    interrupts::without_interrupts(|| {
+        try_with(&FILTERS, |filters| Some(level) <= filters.level(module))
+    })
+    .unwrap_or(true)
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let record = Record {
+        level,
+        module,
+        ticks: time::ticks(),
+        args,
+    };
+    // Recorded once here, as the sinks print without recording.
+    crate::dmesg::record(format_args!("{}\n", record));
+    let written = interrupts::without_interrupts(|| {
+        try_with(&SINKS, |sinks| {
+            for slot in sinks.iter().flatten() {
+                if Some(level) <= slot.level {
+                    slot.sink.write(&record);
+                }
+            }
+        })
+    });
+    if written.is_none() {
+        crate::serial::write(format_args!("{}\n", record));
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => (
+        $crate::log::_log($level, module_path!(), format_args!($($arg)+))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
+
+/// Prints records on the log console, with the level in its colour.
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write(&self, record: &Record) {
+        let color = match record.level {
+            Level::Error => "31",
+            Level::Warn => "33",
+            Level::Info => "32",
+            Level::Debug => "36",
+            Level::Trace => "37",
+        };
+        crate::vga_buffer::write(format_args!("\x1b[{}m{}\x1b[0m\n", color, record));
+    }
+}
+
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, record: &Record) {
+        crate::serial::write(format_args!("{}\n", record));
+    }
+}
+
+/// Keeps the last `MEMORY_LOG_SIZE` bytes of formatted records, one per line.
+pub struct MemorySink {
+    ring: Mutex<Ring>,
+}
+
+struct Ring {
+    bytes: [u8; MEMORY_LOG_SIZE],
+    // Where the next byte goes, and how many are kept.
+    end: usize,
+    len: usize,
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.bytes[self.end] = byte;
+            self.end = (self.end + 1) % MEMORY_LOG_SIZE;
+            self.len = (self.len + 1).min(MEMORY_LOG_SIZE);
+        }
+        Ok(())
+    }
+}
+
+impl MemorySink {
+    pub const fn new() -> MemorySink {
+        MemorySink {
+            ring: Mutex::new(Ring {
+                bytes: [0; MEMORY_LOG_SIZE],
+                end: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    /// Calls `f` with the bytes kept, oldest first, in up to two parts. The
+    /// oldest record may be cut off at the front.
+    pub fn contents(&self, mut f: impl FnMut(&[u8])) {
+        interrupts::without_interrupts(|| {
+            let ring = self.ring.lock();
+            let start = (ring.end + MEMORY_LOG_SIZE - ring.len) % MEMORY_LOG_SIZE;
+            if start + ring.len <= MEMORY_LOG_SIZE {
+                f(&ring.bytes[start..start + ring.len]);
+            } else {
+                f(&ring.bytes[start..]);
+                f(&ring.bytes[..ring.end]);
+            }
+        });
+    }
+
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| self.ring.lock().len = 0);
+    }
+}
+
+impl Default for MemorySink {
+    fn default() -> Self {
+        MemorySink::new()
+    }
+}
+
+impl Sink for MemorySink {
+    fn write(&self, record: &Record) {
+        use core::fmt::Write;
+
+        let _ = writeln!(self.ring.lock(), "{}", record);
+    }
+}
+
+pub static VGA: VgaSink = VgaSink;
+pub static SERIAL: SerialSink = SerialSink;
+pub static MEMORY: MemorySink = MemorySink::new();
+
+/// Adds the `vga`, `serial` and `memory` sinks, taking everything the filters
+/// let through, and applies `BOOT_CONFIG`.
+pub fn init() -> Result<(), ConfigError> {
+    add_sink("vga", &VGA, Some(Level::Trace))?;
+    add_sink("serial", &SERIAL, Some(Level::Trace))?;
+    add_sink("memory", &MEMORY, Some(Level::Trace))?;
+    configure(BOOT_CONFIG)
+}
+
+#[test_case]
+fn test_module_filters() {
+    configure("warn,kernel::fs=debug,kernel::fs::cache=off").unwrap();
+    assert!(enabled(Level::Debug, "kernel::fs"));
+    assert!(!enabled(Level::Trace, "kernel::fs"));
+    assert!(enabled(Level::Debug, "kernel::fs::inode"));
+    assert!(!enabled(Level::Error, "kernel::fs::cache"));
+    assert!(!enabled(Level::Info, "kernel::fsck"));
+    assert!(enabled(Level::Warn, "kernel::fsck"));
+    assert_eq!(configure("kernel=loud"), Err(ConfigError::BadLevel("loud")));
+    assert_eq!(
+        configure("sink:nowhere=info"),
+        Err(ConfigError::UnknownSink("nowhere"))
+    );
+    reset_filters();
+    assert!(!enabled(Level::Debug, "kernel::fs"));
+    assert!(enabled(Level::Info, "kernel::fs::cache"));
+}
+
+#[test_case]
+fn test_sinks() {
+    use core::sync::atomic::AtomicUsize;
+
+    struct CountingSink(AtomicUsize);
+
+    impl Sink for CountingSink {
+        fn write(&self, _record: &Record) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    static COUNTING: CountingSink = CountingSink(AtomicUsize::new(0));
+
+    add_sink("counting", &COUNTING, Some(Level::Info)).unwrap();
+    set_module_level(module_path!(), Some(Level::Trace)).unwrap();
+    crate::info!("counted");
+    crate::debug!("too verbose for the sink");
+    assert_eq!(COUNTING.0.load(Ordering::Relaxed), 1);
+    set_sink_level("counting", None).unwrap();
+    crate::error!("not counted");
+    assert_eq!(COUNTING.0.load(Ordering::Relaxed), 1);
+    remove_sink("counting").unwrap();
+    assert_eq!(
+        remove_sink("counting"),
+        Err(ConfigError::UnknownSink("counting"))
+    );
+    reset_filters();
+}
+
+#[test_case]
+fn test_records_kept_once() {
+    use crate::dmesg;
+
+    let record = Record {
+        level: Level::Info,
+        module: "kernel::fs",
+        ticks: 0,
+        args: format_args!("line"),
+    };
+    let before = dmesg::written();
+    SerialSink.write(&record);
+    VgaSink.write(&record);
+    assert_eq!(dmesg::written(), before);
+
+    set_module_level(module_path!(), Some(Level::Trace)).unwrap();
+    crate::info!("kept");
+    let mut out = [0u8; 64];
+    let len = dmesg::read(&mut out[..dmesg::written() - before]);
+    assert!(out[..len].starts_with(b"[") && out[..len].ends_with(b"rustos::log: kept\n"));
+    reset_filters();
+}
+
+#[test_case]
+fn test_memory_sink() {
+    static SINK: MemorySink = MemorySink::new();
+    let record = |ticks: u64| Record {
+        level: Level::Warn,
+        module: "kernel::fs",
+        ticks,
+        args: format_args!("line"),
+    };
+
+    SINK.write(&record(time::TICK_HZ * 3 / 2));
+    let mut kept = [0u8; 64];
+    let mut len = 0;
+    SINK.contents(|part| {
+        kept[len..len + part.len()].copy_from_slice(part);
+        len += part.len();
+    });
+    assert_eq!(&kept[..len], b"[    1.500] WARN  kernel::fs: line\n");
+
+    for ticks in 0..MEMORY_LOG_SIZE as u64 / 16 {
+        SINK.write(&record(ticks));
+    }
+    let mut total = 0;
+    SINK.contents(|part| total += part.len());
+    assert_eq!(total, MEMORY_LOG_SIZE);
+    SINK.clear();
+    SINK.contents(|part| assert!(part.is_empty()));
+}
@@ -6,13 +6,13 @@
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use rustos::{allocator, println};
+use rustos::{allocator, println, smp, status_bar};
 use x86_64::structures::paging::{PageTable, Translate};
 use x86_64::VirtAddr;
 extern crate alloc;
 use alloc::boxed::Box;
 use rustos::async_task::kb;
-use rustos::async_task::{executor::Executor, executor::SimpleExecutor, Task};
+use rustos::async_task::{executor::Executor, executor::SimpleExecutor};
 
 use rustos::memory;
 use rustos::memory::BootInfoFrameAllocator;
@@ -83,6 +83,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello World{}", "!");
 
     rustos::init();
+    if let Err(err) = rustos::log::init() {
+        println!("bad log configuration: {:?}", err);
+    }
 
     // Example allocator test
 
@@ -96,14 +99,22 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    match smp::init(phys_mem_offset, &mut mapper, &mut frame_allocator) {
+        Ok(cpus) => println!("{} CPUs online", cpus),
+        Err(err) => println!("running on a single CPU: {:?}", err),
+    }
+    memory::set_global(mapper, frame_allocator);
+
     // let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     // unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
     // let x = Box::new(4);
     // println!("{:p}", x);
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(kb::print_keypresses()));
+    executor.set_supervised(true);
+    executor.spawn(example_task());
+    executor.spawn(kb::print_keypresses());
+    status_bar::start();
     executor.run();
 
     #[cfg(test)]
@@ -115,7 +126,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    rustos::async_task::supervisor::recover_from_panic(info);
+    rustos::emergency::begin_panic();
     println!("{}", info);
+    rustos::dmesg::dump_to_serial();
     rustos::hlt();
 }
 
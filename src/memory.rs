@@ -1,14 +1,77 @@
+use crate::smp;
 use bootloader::bootinfo::MemoryMap;
-use x86_64::structures::paging::OffsetPageTable;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameDeallocator, OffsetPageTable};
 use x86_64::{structures::paging::PageTable, VirtAddr};
 use x86_64::{
     structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
-pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// The level 4 table the kernel booted with, which `init` saw first.
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+// Page table and frame allocator handed over by `set_global`.
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+// The CPU running `with_global`, if any.
+static HOLDER: AtomicUsize = AtomicUsize::new(NO_HOLDER);
+const NO_HOLDER: usize = usize::MAX;
+
+/// Where all physical memory is mapped, as passed to `init`.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Hands the page table and frame allocator over to code that cannot have
+/// them passed in, like system calls.
+pub fn set_global(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    interrupts::without_interrupts(|| *MEMORY.lock() = Some((mapper, frame_allocator)));
+}
+
+/// Runs `f` with the page table of the active address space and the frame
+/// allocator given to `set_global`, or returns `None` if there are none yet.
+/// The kernel's table is only ever changed through the mapper handed over.
+pub fn with_global<R>(
+    f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        let (mapper, frame_allocator) = memory.as_mut()?;
+        HOLDER.store(smp::cpu_index(), Ordering::Relaxed);
+        let active = Cr3::read().0;
+        let result = if active == kernel_level_4_frame() {
+            f(mapper, frame_allocator)
+        } else {
+            // The table of a user address space, borrowed only while `f` runs.
+            let offset = physical_memory_offset();
+            let table = unsafe { &mut *(offset + active.start_address().as_u64()).as_mut_ptr() };
+            f(
+                &mut unsafe { OffsetPageTable::new(table, offset) },
+                frame_allocator,
+            )
+        };
+        HOLDER.store(NO_HOLDER, Ordering::Relaxed);
+        Some(result)
+    })
+}
+
+/// Whether the current CPU is in `with_global`, so that calling it again,
+/// as from a page fault, would deadlock.
+pub fn in_with_global() -> bool {
+    HOLDER.load(Ordering::Relaxed) == smp::cpu_index()
+}
 
+/// The frame of the level 4 table the kernel booted with.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
@@ -24,7 +87,6 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -
 }
 
 fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::registers::control::Cr3;
     use x86_64::structures::paging::page_table::FrameError;
 
     let (level_4_table_frame, _) = Cr3::read();
@@ -54,6 +116,13 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 }
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let _ = KERNEL_LEVEL_4_TABLE.compare_exchange(
+        0,
+        Cr3::read().0.start_address().as_u64(),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -86,6 +155,8 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    // Deallocated frames, each holding the address of the next one.
+    free_list: Option<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -93,6 +164,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
         }
     }
 }
@@ -107,12 +179,48 @@ impl BootInfoFrameAllocator {
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Allocates a frame below 1 MiB, which real mode code can address.
+    /// Usable frames in front of it are skipped and stay unused.
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let (index, frame) = self
+            .get_usable_frames()
+            .enumerate()
+            .skip(self.next)
+            .take_while(|(_, frame)| frame.start_address().as_u64() < 0x10_0000)
+            .find(|(_, frame)| frame.start_address().as_u64() != 0)?;
+        self.next = index + 1;
+        Some(frame)
+    }
+}
+
+// Ends the free list; physical address 0 may be a usable frame.
+const FREE_LIST_END: u64 = u64::MAX;
+
+fn free_list_link(frame: PhysFrame) -> *mut u64 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            let next = unsafe { free_list_link(frame).read() };
+            self.free_list =
+                (next != FREE_LIST_END).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            return Some(frame);
+        }
         let frame = self.get_usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map_or(FREE_LIST_END, |f| f.start_address().as_u64());
+        free_list_link(frame).write(next);
+        self.free_list = Some(frame);
+    }
+}
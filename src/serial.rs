@@ -1,10 +1,14 @@
+use crate::emergency::OutputLock;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 
+/// The I/O port base of the first serial port, which `SERIAL1` drives.
+pub const COM1: u16 = 0x3F8;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -12,17 +16,26 @@ lazy_static! {
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
+    crate::dmesg::record(args);
+    write(args);
+}
+
+/// Prints `args` as `serial_print!` does, but leaves it out of `dmesg`, for
+/// output recorded there already.
+pub fn write(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+        OUTPUT.write(&SERIAL1, args, |args| {
+            let _ = unsafe { SerialPort::new(COM1) }.write_fmt(args);
+        })
     });
 }
 
+/// Guards `SERIAL1` for `serial_print!`; see `emergency`.
+pub static OUTPUT: OutputLock = OutputLock::new();
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
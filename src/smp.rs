@@ -0,0 +1,345 @@
+//! Bringing up the application processors (APs).
+//!
+//! The processors are taken from the ACPI MADT and started with the
+//! INIT-SIPI-SIPI sequence. Each AP runs through a small trampoline copied
+//! below 1 MiB which switches from real mode to long mode on the kernel's
+//! page table, loads its own GDT and TSS, the shared IDT and then runs an
+//! `Executor` that steals tasks from the other cores.
+
+use crate::async_task::executor::Executor;
+use crate::memory::BootInfoFrameAllocator;
+use crate::{acpi, apic, gdt, interrupts, syscall};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 16;
+
+// Kernel and double fault stacks of the APs, each CPU getting a 1 MiB slot
+// with unmapped guard pages around the stacks.
+const AP_STACKS_START: u64 = 0x_5000_0000_0000;
+const AP_STACK_SLOT: u64 = 0x10_0000;
+const AP_STACK_PAGES: u64 = 16;
+const AP_DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    Acpi(acpi::AcpiError),
+    NoLowMemory,
+    TrampolineMapped,
+}
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static APIC_IDS: [AtomicU8; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU8 = AtomicU8::new(0);
+    [ZERO; MAX_CPUS]
+};
+// Maps local APIC IDs back to CPU indices.
+static CPU_INDICES: [AtomicU8; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU8 = AtomicU8::new(0);
+    [ZERO; 256]
+};
+static ONLINE: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const OFFLINE: AtomicBool = AtomicBool::new(false);
+    [OFFLINE; MAX_CPUS]
+};
+static DOUBLE_FAULT_STACKS: [AtomicU64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+global_asm!(
+    r#"
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    xorl %ebx, %ebx
+    movw %cs, %bx
+    movw %bx, %ds
+    shll $4, %ebx
+
+    leal (ap_trampoline_gdt - ap_trampoline_start)(%ebx), %eax
+    movl %eax, (ap_trampoline_gdtr - ap_trampoline_start + 2)
+    leal (ap_trampoline_protected - ap_trampoline_start)(%ebx), %eax
+    movl %eax, (ap_trampoline_protected_ptr - ap_trampoline_start)
+    leal (ap_trampoline_long - ap_trampoline_start)(%ebx), %eax
+    movl %eax, (ap_trampoline_long_ptr - ap_trampoline_start)
+
+    lgdtl (ap_trampoline_gdtr - ap_trampoline_start)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl *(ap_trampoline_protected_ptr - ap_trampoline_start)
+
+.code32
+ap_trampoline_protected:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movl %cr4, %eax
+    orl $0x20, %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline_start)(%ebx), %eax
+    movl %eax, %cr3
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $0x900, %eax
+    wrmsr
+    movl %cr0, %eax
+    orl $0x80010000, %eax
+    movl %eax, %cr0
+    ljmpl *(ap_trampoline_long_ptr - ap_trampoline_start)(%ebx)
+
+.code64
+ap_trampoline_long:
+    movl %ebx, %ebx
+    movq (ap_trampoline_stack - ap_trampoline_start)(%rbx), %rsp
+    movq (ap_trampoline_cpu - ap_trampoline_start)(%rbx), %rdi
+    movq (ap_trampoline_entry - ap_trampoline_start)(%rbx), %rax
+    callq *%rax
+1:
+    hlt
+    jmp 1b
+
+.balign 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_trampoline_gdtr:
+    .word 4 * 8 - 1
+    .long 0
+ap_trampoline_protected_ptr:
+    .long 0
+    .word 0x08
+ap_trampoline_long_ptr:
+    .long 0
+    .word 0x18
+
+.balign 8
+.global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .quad 0
+.global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+.global ap_trampoline_entry
+ap_trampoline_entry:
+    .quad 0
+.global ap_trampoline_cpu
+ap_trampoline_cpu:
+    .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Index of the executing CPU, 0 being the bootstrap processor.
+pub fn cpu_index() -> usize {
+    if apic::is_initialized() {
+        usize::from(CPU_INDICES[usize::from(apic::id())].load(Ordering::Relaxed))
+    } else {
+        0
+    }
+}
+
+/// Number of CPUs that are up and running, including the bootstrap
+/// processor.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+fn is_online(cpu: usize) -> bool {
+    cpu < MAX_CPUS && ONLINE[cpu].load(Ordering::Acquire)
+}
+
+/// Interrupts `cpu` so that it leaves `hlt` and checks for work.
+pub fn wake(cpu: usize) {
+    if cpu_count() > 1 && is_online(cpu) && cpu != cpu_index() {
+        let apic_id = APIC_IDS[cpu].load(Ordering::Relaxed);
+        apic::send_fixed(apic_id, interrupts::WAKEUP_VECTOR);
+    }
+}
+
+/// Interrupts all other CPUs, e.g. because a task can be stolen.
+pub fn wake_others() {
+    if cpu_count() > 1 {
+        let current = cpu_index();
+        (0..MAX_CPUS).filter(|&cpu| cpu != current).for_each(wake);
+    }
+}
+
+/// Starts all application processors listed in the MADT. Returns the number
+/// of CPUs online afterwards. All share the trampoline, so once one does not
+/// come up in time the rest are not started: it might still come up later
+/// and must not find the data of the next one there.
+pub fn init(
+    physical_memory_offset: VirtAddr,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<usize, SmpError> {
+    let madt = acpi::read_madt(physical_memory_offset).map_err(SmpError::Acpi)?;
+    apic::init(
+        madt.local_apic_address,
+        physical_memory_offset,
+        mapper,
+        frame_allocator,
+    );
+    apic::enable();
+    let bsp = apic::id();
+    APIC_IDS[0].store(bsp, Ordering::Relaxed);
+    ONLINE[0].store(true, Ordering::Release);
+
+    let trampoline = copy_trampoline(physical_memory_offset, mapper, frame_allocator)?;
+    let page = (trampoline.start_address().as_u64() >> 12) as u8;
+
+    let aps = madt.processors.iter().filter(|p| p.apic_id != bsp);
+    for (cpu, processor) in (1..MAX_CPUS).zip(aps) {
+        APIC_IDS[cpu].store(processor.apic_id, Ordering::Relaxed);
+        CPU_INDICES[usize::from(processor.apic_id)].store(cpu as u8, Ordering::Relaxed);
+        let stack_end = map_ap_stacks(cpu, mapper, frame_allocator);
+        unsafe {
+            let field = |symbol: &u8| {
+                let offset = symbol as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+                (physical_memory_offset + trampoline.start_address().as_u64() + offset)
+                    .as_mut_ptr::<u64>()
+            };
+            field(&ap_trampoline_cr3).write_volatile(Cr3::read().0.start_address().as_u64());
+            field(&ap_trampoline_stack).write_volatile(stack_end.as_u64());
+            field(&ap_trampoline_entry)
+                .write_volatile(ap_main as extern "C" fn(u64) -> ! as usize as u64);
+            field(&ap_trampoline_cpu).write_volatile(cpu as u64);
+        }
+
+        apic::send_init(processor.apic_id);
+        apic::delay_us(10_000);
+        for _ in 0..2 {
+            apic::send_startup(processor.apic_id, page);
+            apic::delay_us(200);
+            if wait_online(cpu, 0) {
+                break;
+            }
+        }
+        if !wait_online(cpu, 100_000) {
+            break;
+        }
+        CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    Ok(cpu_count())
+}
+
+fn wait_online(cpu: usize, mut timeout_us: u32) -> bool {
+    while !is_online(cpu) {
+        if timeout_us == 0 {
+            return false;
+        }
+        apic::delay_us(10);
+        timeout_us = timeout_us.saturating_sub(10);
+    }
+    true
+}
+
+// Copies the trampoline into a frame below 1 MiB, which a startup IPI can
+// point at, and identity-maps it for the switch to paging.
+fn copy_trampoline(
+    physical_memory_offset: VirtAddr,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<PhysFrame, SmpError> {
+    let frame = frame_allocator
+        .allocate_low_frame()
+        .ok_or(SmpError::NoLowMemory)?;
+    let phys = frame.start_address();
+
+    match mapper.translate_addr(VirtAddr::new(phys.as_u64())) {
+        Some(addr) if addr == phys => {}
+        Some(_) => return Err(SmpError::TrampolineMapped),
+        None => unsafe {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            mapper
+                .identity_map(frame, flags, frame_allocator)
+                .map_err(|_| SmpError::TrampolineMapped)?
+                .flush();
+        },
+    }
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        let dst = (physical_memory_offset + phys.as_u64()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(start, dst, len);
+    }
+    Ok(frame)
+}
+
+// Maps the stacks of `cpu` and returns the end of its kernel stack.
+fn map_ap_stacks(
+    cpu: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> VirtAddr {
+    let slot = AP_STACKS_START + cpu as u64 * AP_STACK_SLOT;
+    let stack_start = VirtAddr::new(slot + 4096);
+    let double_fault_start = stack_start + (AP_STACK_PAGES + 1) * 4096;
+
+    for (start, pages) in [
+        (stack_start, AP_STACK_PAGES),
+        (double_fault_start, AP_DOUBLE_FAULT_STACK_PAGES),
+    ] {
+        let first = Page::<Size4KiB>::containing_address(start);
+        for page in Page::range(first, first + pages) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .expect("out of frames for AP stacks");
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, frame_allocator)
+                    .expect("failed to map AP stack")
+                    .flush();
+            }
+        }
+    }
+
+    let double_fault_end = double_fault_start + AP_DOUBLE_FAULT_STACK_PAGES * 4096;
+    DOUBLE_FAULT_STACKS[cpu].store(double_fault_end.as_u64(), Ordering::Relaxed);
+    stack_start + AP_STACK_PAGES * 4096
+}
+
+extern "C" fn ap_main(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    gdt::init_ap(VirtAddr::new(
+        DOUBLE_FAULT_STACKS[cpu].load(Ordering::Relaxed),
+    ));
+    interrupts::init_idt();
+    syscall::init();
+    apic::enable();
+    ONLINE[cpu].store(true, Ordering::Release);
+    x86_64::instructions::interrupts::enable();
+
+    Executor::new().run();
+}
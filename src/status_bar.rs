@@ -0,0 +1,39 @@
+//! A status bar on the top row of the screen, with the uptime, heap usage,
+//! number of tasks and the keyboard lock keys.
+
+use crate::allocator::{self, HEAP_SIZE};
+use crate::async_task::{executor, kb};
+use crate::{thread, time, vga_buffer};
+
+/// How often the thread started by `start` redraws the status bar.
+pub const REFRESH_MS: u64 = 500;
+
+/// Redraws the status bar with the current values.
+pub fn refresh() {
+    let seconds = time::uptime_ms() / 1000;
+    let locks = kb::lock_state();
+    let lock = |on: bool, name: &'static str| if on { name } else { "" };
+    vga_buffer::set_status(format_args!(
+        " up {}:{:02}:{:02} | heap {}/{} KiB | tasks {} | {} {} {}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        allocator::heap_used().div_ceil(1024),
+        HEAP_SIZE / 1024,
+        executor::live_task_count(),
+        lock(locks.caps_lock, "CAPS"),
+        lock(locks.num_lock, "NUM"),
+        lock(locks.scroll_lock, "SCROLL"),
+    ));
+}
+
+/// Reserves the top row of the screen and starts a thread refreshing it
+/// every `REFRESH_MS`.
+pub fn start() -> thread::JoinHandle<()> {
+    vga_buffer::set_status_bar(true);
+    refresh();
+    thread::spawn_thread(|| loop {
+        thread::sleep(REFRESH_MS);
+        refresh();
+    })
+}
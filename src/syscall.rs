@@ -0,0 +1,186 @@
+//! System calls through `syscall`/`sysret`.
+//!
+//! The number goes in `rax` and up to five arguments in `rdi`, `rsi`, `rdx`,
+//! `r10` and `r8`; the result comes back in `rax`, with errors returned as
+//! the negated `Error` code. `rcx`, `r11` and the other argument registers
+//! are clobbered.
+
+use crate::async_task::kb;
+use crate::user::{self, Exit};
+use crate::{address_space, gdt, memory, print, smp, thread, time};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+pub const WRITE: u64 = 0;
+pub const READ_KEY: u64 = 1;
+pub const SLEEP: u64 = 2;
+pub const EXIT: u64 = 3;
+pub const TIME: u64 = 4;
+pub const ALLOC: u64 = 5;
+
+/// The most `ALLOC` maps in one call.
+pub const MAX_ALLOC_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Error {
+    NoSuchCall = 1,
+    BadAddress = 2,
+    WouldBlock = 3,
+    OutOfMemory = 4,
+}
+
+impl Error {
+    /// The value user code sees in `rax`.
+    pub fn as_return_value(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+type Handler = fn([u64; 5]) -> Result<u64, Error>;
+
+static HANDLERS: [Handler; 6] = [write, read_key, sleep, exit, get_time, alloc];
+
+// Per-CPU scratch space for the entry code, found through the kernel GS base:
+// the address of the CPU's TSS, to take the kernel stack from RSP0, and the
+// user stack pointer while switching stacks.
+#[repr(C)]
+struct EntryScratch {
+    tss: AtomicU64,
+    user_rsp: AtomicU64,
+}
+
+static SCRATCH: [EntryScratch; smp::MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: EntryScratch = EntryScratch {
+        tss: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+    };
+    [EMPTY; smp::MAX_CPUS]
+};
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// SFMASK clears IF on entry, so nothing can interrupt the entry code before
+// it is on the kernel stack. GS is swapped back right away, as the calling
+// thread may be preempted during the call.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[8], rsp",
+    "mov rsp, gs:[0]",
+    // RSP0 of the TSS.
+    "mov rsp, [rsp + 4]",
+    "push qword ptr gs:[8]",
+    "swapgs",
+    "push rcx",
+    "push r11",
+    "sub rsp, 8",
+    "sti",
+    "mov r9, r8",
+    "mov r8, r10",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "cli",
+    "add rsp, 8",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    dispatch = sym dispatch,
+);
+
+/// Enables `syscall` on the current CPU.
+pub fn init() {
+    let selectors = gdt::selectors();
+    let scratch = &SCRATCH[smp::cpu_index()];
+    scratch
+        .tss
+        .store(gdt::tss_address().as_u64(), Ordering::Relaxed);
+    KernelGsBase::write(VirtAddr::from_ptr(scratch));
+    LStar::write(VirtAddr::new(
+        syscall_entry as unsafe extern "C" fn() as usize as u64,
+    ));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout does not fit sysret");
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn dispatch(number: u64, a0: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> u64 {
+    let result = match HANDLERS.get(number as usize) {
+        Some(handler) => handler([a0, a1, a2, a3, a4]),
+        None => Err(Error::NoSuchCall),
+    };
+    result.unwrap_or_else(Error::as_return_value)
+}
+
+/// `write(buf, len)`: prints the UTF-8 string at `buf` to the console.
+fn write([buf, len, ..]: [u64; 5]) -> Result<u64, Error> {
+    if !user::is_accessible(buf, len, false) {
+        return Err(Error::BadAddress);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    let text = core::str::from_utf8(bytes).map_err(|_| Error::BadAddress)?;
+    print!("{}", text);
+    Ok(len)
+}
+
+/// `read_key()`: the next character typed, without waiting for one.
+fn read_key(_: [u64; 5]) -> Result<u64, Error> {
+    kb::read_char().map(u64::from).ok_or(Error::WouldBlock)
+}
+
+/// `sleep(ms)`
+fn sleep([ms, ..]: [u64; 5]) -> Result<u64, Error> {
+    thread::sleep(ms);
+    Ok(0)
+}
+
+/// `exit(status)`: returns `status` from `user::run`.
+fn exit([status, ..]: [u64; 5]) -> Result<u64, Error> {
+    user::exit(Exit::Exited(status))
+}
+
+/// `time()`: milliseconds since boot.
+fn get_time(_: [u64; 5]) -> Result<u64, Error> {
+    Ok(time::uptime_ms())
+}
+
+/// `alloc(size)`: maps `size` bytes of zeroed, writable memory, up to
+/// `MAX_ALLOC_SIZE`, and returns its address.
+fn alloc([size, ..]: [u64; 5]) -> Result<u64, Error> {
+    if size > MAX_ALLOC_SIZE {
+        return Err(Error::OutOfMemory);
+    }
+    let pages = size.div_ceil(4096).max(1);
+    let len = pages * 4096;
+    let start = address_space::reserve_heap(len).ok_or(Error::OutOfMemory)?;
+
+    let page = Page::containing_address(VirtAddr::new(start));
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mapped = memory::with_global(|mapper, frame_allocator| {
+        user::map_user_pages(page, pages, flags, mapper, frame_allocator)
+    });
+    if !matches!(mapped, Some(Ok(()))) {
+        address_space::unreserve_heap(start, len);
+        return Err(Error::OutOfMemory);
+    }
+    unsafe { core::ptr::write_bytes(start as *mut u8, 0, len as usize) };
+    Ok(start)
+}
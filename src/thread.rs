@@ -0,0 +1,299 @@
+//! Preemptive kernel threads for work that cannot be written as a future,
+//! like long CPU-bound loops.
+//!
+//! Every thread has its own stack. The timer interrupt switches round-robin
+//! between the runnable threads on the bootstrap processor; the code that
+//! called `spawn_thread` first, usually `kernel_main` and its executor,
+//! becomes the boot thread and takes part in the rotation. Threads only run
+//! on the bootstrap processor.
+
+use crate::{context, gdt, smp, time};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::fmt;
+use core::ops::Bound;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+pub const STACK_SIZE: usize = 4096 * 4;
+
+// Kept in the lowest word of every thread stack. The stacks are on the heap
+// without a guard page, so an overflow is only caught when a switch away
+// from the thread finds this overwritten.
+const STACK_CANARY: u64 = 0x57ac_6b0f_c0de_57ac;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    /// Sleeping until the given tick.
+    Sleeping(u64),
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    state: State,
+    rsp: u64,
+    // None for the boot thread, which runs on the stack it was started on.
+    stack: Option<Box<[u64]>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Thread {
+    fn stack_intact(&self) -> bool {
+        let canary = |stack: &[u64]| unsafe { ptr::read_volatile(&stack[0]) };
+        self.stack
+            .as_deref()
+            .is_none_or(|stack| canary(stack) == STACK_CANARY)
+    }
+}
+
+struct Scheduler {
+    // Boxed so that the saved stack pointers do not move.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    current: ThreadId,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    threads: BTreeMap::new(),
+    current: ThreadId::BOOT,
+});
+// `Scheduler::current`, readable without the lock, as by a panic handler.
+static CURRENT: AtomicU64 = AtomicU64::new(ThreadId::BOOT.0);
+
+impl Scheduler {
+    fn is_runnable(&self, thread: &Thread, now: u64) -> bool {
+        match thread.state {
+            State::Ready => true,
+            State::Sleeping(until) => now >= until,
+            State::Joining(id) => self
+                .threads
+                .get(&id)
+                .is_none_or(|t| t.state == State::Finished),
+            State::Finished => false,
+        }
+    }
+
+    // The first runnable thread after the current one, wrapping around.
+    fn next(&self, now: u64) -> Option<ThreadId> {
+        let after = self
+            .threads
+            .range((Bound::Excluded(self.current), Bound::Unbounded));
+        let before = self.threads.range(..=self.current);
+        after
+            .chain(before)
+            .find(|(_, thread)| self.is_runnable(thread, now))
+            .map(|(&id, _)| id)
+    }
+
+    fn set_state(&mut self, state: State) {
+        if let Some(thread) = self.threads.get_mut(&self.current) {
+            thread.state = state;
+        }
+    }
+}
+
+// Switches to the next runnable thread, if that is not the current one.
+// Interrupts must be disabled.
+fn schedule(mut scheduler: MutexGuard<Scheduler>) {
+    let next = match scheduler.next(time::ticks()) {
+        Some(next) if next != scheduler.current => next,
+        _ => return,
+    };
+    let current = scheduler.current;
+    let old_rsp: *mut u64 = match scheduler.threads.get_mut(&current) {
+        Some(thread) if !thread.stack_intact() => {
+            drop(scheduler);
+            panic!("thread {} overflowed its stack", current);
+        }
+        Some(thread) => &mut thread.rsp,
+        None => return,
+    };
+    let thread = scheduler.threads.get_mut(&next).unwrap();
+    thread.state = State::Ready;
+    let new_rsp = thread.rsp;
+    scheduler.current = next;
+    CURRENT.store(next.0, Ordering::Relaxed);
+    drop(scheduler);
+    let kernel_stack = gdt::kernel_stack();
+    unsafe { context::switch(old_rsp, new_rsp) };
+    // Back on this thread, which may be in the middle of running user code.
+    gdt::set_kernel_stack(kernel_stack);
+}
+
+/// Called by the timer interrupt to move on to the next thread.
+pub(crate) fn preempt() {
+    // Skip this tick if another CPU is spawning a thread.
+    if let Some(scheduler) = SCHEDULER.try_lock() {
+        schedule(scheduler);
+    }
+}
+
+extern "C" fn thread_start() -> ! {
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().entry.take()
+    };
+    // Threads are first switched to with interrupts disabled.
+    interrupts::enable();
+    entry.unwrap()();
+    exit();
+}
+
+// Frees the stacks of finished threads, which is not done by `schedule` to
+// keep the timer interrupt from deallocating.
+fn reap() {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler
+            .threads
+            .retain(|&id, thread| id == current || thread.state != State::Finished);
+    });
+}
+
+/// The thread running on the bootstrap processor.
+pub fn current() -> ThreadId {
+    ThreadId(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Starts a thread running `f` and returns a handle to wait for its result.
+pub fn spawn_thread<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap();
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let entry = Box::new(move || {
+        let value = f();
+        interrupts::without_interrupts(|| *slot.lock() = Some(value));
+    });
+    let mut stack = vec![0u64; STACK_SIZE / 8].into_boxed_slice();
+    stack[0] = STACK_CANARY;
+    let stack_end = stack.as_ptr() as u64 + STACK_SIZE as u64;
+    let thread = Box::new(Thread {
+        state: State::Ready,
+        rsp: unsafe { context::init_stack(stack_end, thread_start) },
+        stack: Some(stack),
+        entry: Some(entry),
+    });
+    let boot = Box::new(Thread {
+        state: State::Ready,
+        rsp: 0,
+        stack: None,
+        entry: None,
+    });
+
+    let id = ThreadId::new();
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads.entry(ThreadId::BOOT).or_insert(boot);
+        scheduler.threads.insert(id, thread);
+    });
+    JoinHandle { id, result }
+}
+
+/// Gives the rest of the time slice to the next runnable thread.
+pub fn yield_now() {
+    if smp::cpu_index() == 0 {
+        interrupts::without_interrupts(|| schedule(SCHEDULER.lock()));
+    }
+}
+
+/// Puts the current thread to sleep for at least `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    let until = time::ticks() + time::ms_to_ticks(ms);
+    block(State::Sleeping(until), || time::ticks() >= until);
+}
+
+// Parks the current thread in `state` until `done` returns true, halting
+// while no other thread can run. Other CPUs have no timer interrupt and
+// busy-wait instead.
+fn block(state: State, done: impl Fn() -> bool) {
+    if smp::cpu_index() != 0 {
+        while !done() {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    reap();
+    loop {
+        interrupts::disable();
+        if done() {
+            break;
+        }
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.set_state(state);
+        schedule(scheduler);
+        if done() {
+            break;
+        }
+        interrupts::enable_and_hlt();
+    }
+    SCHEDULER.lock().set_state(State::Ready);
+    interrupts::enable();
+}
+
+/// Ends the current thread; its result, if any, has already been stored.
+fn exit() -> ! {
+    interrupts::disable();
+    loop {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.set_state(State::Finished);
+        schedule(scheduler);
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| self.result.lock().is_some())
+    }
+
+    /// Blocks the current thread until the thread has finished and returns
+    /// its result.
+    pub fn join(self) -> T {
+        block(State::Joining(self.id), || self.is_finished());
+        interrupts::without_interrupts(|| self.result.lock().take()).unwrap()
+    }
+}
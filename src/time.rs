@@ -0,0 +1,43 @@
+//! System time kept by the programmable interval timer.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Timer interrupts per second.
+pub const TICK_HZ: u64 = 100;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 of the PIT to fire `TICK_HZ` times per second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICK_HZ) as u16;
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel: Port<u8> = Port::new(PIT_CHANNEL_0);
+    unsafe {
+        // Channel 0, low byte then high byte, rate generator.
+        command.write(0x34);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+}
+
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ
+}
+
+/// Rounds up, so that sleeping for any non-zero time waits at least a tick.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TICK_HZ).div_ceil(1000)
+}
@@ -0,0 +1,218 @@
+//! Running code in ring 3.
+//!
+//! `run` enters user mode with `iretq` and returns once the user code exits
+//! through `int 0x80` or faults. Interrupts from user mode arrive on the
+//! stack in RSP0, which is pointed below the frames still in use by `run`,
+//! and the exit handlers jump back into `run` with `context::resume`.
+
+use crate::context::{self, JumpBuffer};
+use crate::{gdt, memory};
+use core::arch::{asm, global_asm};
+use core::ptr::{addr_of, addr_of_mut};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+/// User mappings live in their own level 4 entries, apart from the kernel.
+pub const USER_SPACE_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_2000_0000_0000;
+
+/// Interrupt vector user code raises to exit, with the status in `rax`.
+pub const EXIT_VECTOR: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Exited(u64),
+    PageFault { address: u64, error: u64 },
+    GeneralProtection(u64),
+    InvalidOpcode,
+}
+
+struct UserContext {
+    buf: JumpBuffer,
+    exit: Option<Exit>,
+}
+
+/// Maps `count` fresh pages from `start` accessible to user mode, with the
+/// page tables on the way marked user-accessible as well. If one cannot be
+/// mapped, those mapped before are unmapped and freed again.
+pub fn map_user_pages(
+    start: Page,
+    count: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let end = start + count;
+    assert!(
+        start.start_address().as_u64() >= USER_SPACE_START
+            && end.start_address().as_u64() <= USER_SPACE_END,
+        "user pages outside of user space"
+    );
+
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let parent_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for page in Page::range(start, end) {
+        let mapped = match frame_allocator.allocate_frame() {
+            Some(frame) => unsafe {
+                mapper
+                    .map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)
+                    .map(|flush| flush.flush())
+                    .inspect_err(|_| frame_allocator.deallocate_frame(frame))
+            },
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        if let Err(error) = mapped {
+            unmap_user_pages(start, page - start, mapper, frame_allocator);
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps the pages mapped in the `count` pages from `start` and frees
+/// their frames, which no other address space may map. The page tables
+/// stay.
+pub fn unmap_user_pages(
+    start: Page,
+    count: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    for page in Page::range(start, start + count) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_deallocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Whether user code may access the `len` bytes at `start`, and write to
+/// them if `write` is set. System calls check pointers they are given with
+/// this before touching them. Nothing is before `memory::set_global`.
+pub fn is_accessible(start: u64, len: u64, write: bool) -> bool {
+    let end = match start.checked_add(len) {
+        Some(end) if start >= USER_SPACE_START && end <= USER_SPACE_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    memory::with_global(|mapper, _| {
+        Page::range_inclusive(first, last).all(|page| {
+            match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => {
+                    flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                        && (!write || flags.contains(PageTableFlags::WRITABLE))
+                }
+                _ => false,
+            }
+        })
+    })
+    .unwrap_or(false)
+}
+
+/// Runs the user code at `entry` on the stack ending at `stack_end` until it
+/// exits or faults.
+///
+/// # Safety
+///
+/// `entry` and the stack must be mapped user-accessible. The user code can
+/// read and write everything else that is.
+pub unsafe fn run(entry: VirtAddr, stack_end: VirtAddr) -> Exit {
+    let interrupts_enabled = interrupts::are_enabled();
+    let mut context = UserContext {
+        buf: JumpBuffer::default(),
+        exit: None,
+    };
+    let context: *mut UserContext = &mut context;
+
+    let previous = gdt::kernel_stack();
+    context::catch(addr_of_mut!((*context).buf), || {
+        enter(entry, stack_end, context)
+    });
+    gdt::set_kernel_stack(previous);
+
+    // The exit handlers resume with interrupts disabled.
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    addr_of!((*context).exit)
+        .read_volatile()
+        .expect("user code returned without exit")
+}
+
+unsafe fn enter(entry: VirtAddr, stack_end: VirtAddr, context: *mut UserContext) -> ! {
+    // Everything below this frame is free once in user mode. RSP0 goes there,
+    // with the context pointer stored right above it for the exit handlers;
+    // no interrupt may push onto it before `iretq`.
+    interrupts::disable();
+    let rsp: u64;
+    asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+    let kernel_stack = (rsp - 64) & !0xf;
+    (kernel_stack as *mut *mut UserContext).write(context);
+    gdt::set_kernel_stack(VirtAddr::new(kernel_stack));
+
+    let selectors = gdt::selectors();
+    let code = u64::from(selectors.user_code_selector.0);
+    let data = u64::from(selectors.user_data_selector.0);
+    // Interrupts enabled plus the reserved bit 1.
+    let rflags: u64 = 0x202;
+    asm!(
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        "iretq",
+        data = in(reg) data,
+        stack = in(reg) stack_end.as_u64(),
+        rflags = in(reg) rflags,
+        code = in(reg) code,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
+
+/// Ends the user code running on this CPU and returns `exit` from its `run`.
+/// Must be called from an interrupt handler that interrupted user mode or
+/// from a system call.
+pub(crate) fn exit(exit: Exit) -> ! {
+    unsafe {
+        let context = gdt::kernel_stack().as_ptr::<*mut UserContext>().read();
+        addr_of_mut!((*context).exit).write_volatile(Some(exit));
+        context::resume(addr_of!((*context).buf))
+    }
+}
+
+extern "C" fn exit_with_status(status: u64) -> ! {
+    exit(Exit::Exited(status))
+}
+
+extern "C" {
+    fn user_exit_entry();
+}
+
+// The frame pushed by the CPU is abandoned, so the entry only has to pass on
+// `rax` and realign the stack.
+global_asm!(
+    ".global user_exit_entry",
+    "user_exit_entry:",
+    "mov rdi, rax",
+    "and rsp, -16",
+    "call {exit}",
+    "ud2",
+    exit = sym exit_with_status,
+);
+
+pub(crate) fn exit_entry() -> VirtAddr {
+    VirtAddr::new(user_exit_entry as unsafe extern "C" fn() as usize as u64)
+}
@@ -1,11 +1,33 @@
+use crate::emergency::OutputLock;
 use core::fmt;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
 
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
+// CRT controller registers driving the hardware cursor.
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_END: u8 = 0x0b;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// Virtual consoles, switched between with Alt+F1 and on. The first one
+/// shows the kernel's `print!` output; the others are for interactive use.
+pub const CONSOLE_COUNT: usize = 6;
+pub const LOG_CONSOLE: usize = 0;
+
+/// Lines scrolled off the top of the screen that can be kept at most.
+pub const MAX_SCROLLBACK: usize = 500;
+pub const DEFAULT_SCROLLBACK: usize = 200;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -50,14 +72,286 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+const BLANK_LINE: Line = [ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0),
+}; BUFFER_WIDTH];
+
+// Lines that scrolled off the screen, in a ring of `depth` entries, and the
+// live screen while an older part is shown.
+struct Scrollback {
+    lines: [Line; MAX_SCROLLBACK],
+    start: usize,
+    len: usize,
+    depth: usize,
+    // How many lines up from the live view the screen shows.
+    offset: usize,
+    live: [Line; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [BLANK_LINE; MAX_SCROLLBACK],
+            start: 0,
+            len: 0,
+            depth: DEFAULT_SCROLLBACK,
+            offset: 0,
+            live: [BLANK_LINE; BUFFER_HEIGHT],
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.len < self.depth {
+            self.lines[(self.start + self.len) % self.depth] = line;
+            self.len += 1;
+        } else {
+            self.lines[self.start] = line;
+            self.start = (self.start + 1) % self.depth;
+        }
+    }
+
+    // Line `index` of the history followed by the live screen from row
+    // `top`.
+    fn line(&self, index: usize, top: usize) -> &Line {
+        match index.checked_sub(self.len) {
+            None => &self.lines[(self.start + index) % self.depth],
+            Some(row) => &self.live[top + row],
+        }
+    }
+
+    // Keeps the newest lines, moved to the start of the ring.
+    fn set_depth(&mut self, depth: usize) {
+        let depth = depth.min(MAX_SCROLLBACK);
+        if self.depth > 0 {
+            self.lines[..self.depth].rotate_left(self.start);
+        }
+        let kept = self.len.min(depth);
+        self.lines.copy_within(self.len - kept..self.len, 0);
+        self.start = 0;
+        self.len = kept;
+        self.depth = depth;
+    }
+}
+
+static mut SCROLLBACK: Scrollback = Scrollback::new();
+
+// Whether the top row of the screen is the status bar, which writers leave
+// alone.
+static STATUS_BAR: AtomicBool = AtomicBool::new(false);
+static STATUS_LOCK: Mutex<()> = Mutex::new(());
+
+// The first row writers use.
+fn top_row() -> usize {
+    usize::from(STATUS_BAR.load(Ordering::Relaxed))
+}
+
+// Where each console's screen is kept while another one is shown.
+static mut BACKING: [[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLE_COUNT] =
+    [[BLANK_LINE; BUFFER_HEIGHT]; CONSOLE_COUNT];
+
+fn vga_buffer() -> &'static mut Buffer {
+    unsafe { &mut *(0xb8000 as *mut Buffer) }
+}
+
+// Only the writer of `console` may use it.
+unsafe fn backing_buffer(console: usize) -> &'static mut Buffer {
+    &mut *(addr_of_mut!(BACKING[console]) as *mut Buffer)
+}
+
+fn write_crtc(register: u8, value: u8) {
+    unsafe {
+        Port::new(CRTC_INDEX).write(register);
+        Port::new(CRTC_DATA).write(value);
+    }
+}
+
+fn read_crtc(register: u8) -> u8 {
+    unsafe {
+        Port::new(CRTC_INDEX).write(register);
+        Port::new(CRTC_DATA).read()
+    }
+}
+
+/// The scan lines of a character cell the hardware cursor covers, from 0 at
+/// the top to 15 at the bottom.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorShape {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl CursorShape {
+    pub const UNDERLINE: CursorShape = CursorShape { start: 14, end: 15 };
+    pub const BLOCK: CursorShape = CursorShape { start: 0, end: 15 };
+}
+
+const ESCAPE: u8 = 0x1b;
+const MAX_ESCAPE_PARAMS: usize = 4;
+
+// Where `Writer` is in an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    // After ESC.
+    Started,
+    // After ESC [, with the numeric parameters read so far.
+    Csi {
+        params: [u16; MAX_ESCAPE_PARAMS],
+        count: usize,
+    },
+}
+
+// ANSI color numbers in SGR sequences, without and with the bright bit.
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+// The characters code page 437 has in place of control characters, from 0x01
+// to 0x1f and at 0x7f, and from 0x80 on.
+const CP437_CONTROL: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕', '‼',
+    '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+const CP437_DELETE: char = '⌂';
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+// The glyph for `c` in code page 437, the font of the VGA text mode, taking
+// a few look-alikes for characters it does not have.
+pub(crate) fn to_cp437(c: char) -> Option<u8> {
+    let alias = match c {
+        'β' => 'ß',
+        'μ' => 'µ',
+        '\u{2126}' => 'Ω',
+        'ϕ' | '∅' => 'φ',
+        '∈' => 'ε',
+        _ => c,
+    };
+    if alias == CP437_DELETE {
+        return Some(0x7f);
+    }
+    if let Some(i) = CP437_CONTROL.iter().position(|&g| g == alias) {
+        return Some(i as u8 + 1);
+    }
+    CP437_HIGH
+        .iter()
+        .position(|&g| g == alias)
+        .map(|i| i as u8 + 0x80)
+}
+
+fn ansi_color(number: u16) -> u8 {
+    ANSI_COLORS[usize::from(number)] as u8
+}
+
+/// Writes to the VGA text buffer.
+///
+/// `write_string` understands the ANSI escape sequences for SGR colors
+/// (`ESC [ ... m`, including bold as bright), cursor movement (`A`, `B`, `C`,
+/// `D`, `G`, `H`, `f`), clearing the screen and lines (`J`, `K`) and saving
+/// and restoring the cursor (`s`, `u`, `ESC 7`, `ESC 8`). Others are dropped.
 pub struct Writer {
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
+    default_color: ColorCode,
+    bold: bool,
+    escape: Escape,
+    saved_position: (usize, usize),
     buffer: &'static mut Buffer,
+    // Whether `buffer` is the screen rather than a console's backing buffer.
+    visible: bool,
+    scrollback: Option<&'static mut Scrollback>,
 }
 
 impl Writer {
+    fn new(
+        color_code: ColorCode,
+        buffer: &'static mut Buffer,
+        visible: bool,
+        scrollback: Option<&'static mut Scrollback>,
+    ) -> Writer {
+        Writer {
+            row_position: BUFFER_HEIGHT - 1,
+            column_position: 0,
+            color_code,
+            default_color: color_code,
+            bold: false,
+            escape: Escape::None,
+            saved_position: (0, 0),
+            buffer,
+            visible,
+            scrollback,
+        }
+    }
+
+    // A writer for a console that is not shown, starting out blank.
+    fn off_screen(color_code: ColorCode, buffer: &'static mut Buffer) -> Writer {
+        let mut writer = Writer::new(color_code, buffer, false, None);
+        writer.clear_cells(0, BUFFER_HEIGHT * BUFFER_WIDTH);
+        writer
+    }
+
+    // Moves the screen contents to `backing` and writes there from now on.
+    fn hide(&mut self, backing: &'static mut Buffer) {
+        self.restore_live_view();
+        for row in top_row()..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                backing.chars[row][col].write(self.buffer.chars[row][col].read());
+            }
+        }
+        self.buffer = backing;
+        self.visible = false;
+    }
+
+    // Puts the contents of the backing buffer on the screen and writes there.
+    fn show(&mut self) {
+        let screen = vga_buffer();
+        for row in top_row()..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                screen.chars[row][col].write(self.buffer.chars[row][col].read());
+            }
+        }
+        self.buffer = screen;
+        self.visible = true;
+        self.update_cursor();
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.update_cursor();
+    }
+
+    fn put_byte(&mut self, byte: u8) {
+        self.restore_live_view();
         match byte {
             b'\n' => self.new_line(),
             byte => {
@@ -65,7 +359,8 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                self.row_position = self.row_position.max(top_row());
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -80,7 +375,20 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        let top = top_row();
+        if let Some(scrollback) = self.scrollback.as_mut() {
+            let mut line = BLANK_LINE;
+            for (col, c) in line.iter_mut().enumerate() {
+                *c = self.buffer.chars[top][col].read();
+            }
+            scrollback.push(line);
+        }
+        for row in top + 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
                 self.buffer.chars[row - 1][col].write(character);
@@ -88,7 +396,53 @@ impl Writer {
         }
 
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+    }
+
+    /// Moves to `row` and `column`, where the next character goes; both are
+    /// clamped to the screen, below the status bar if there is one.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        self.restore_live_view();
+        self.row_position = row.clamp(top_row(), BUFFER_HEIGHT - 1);
+        self.column_position = column.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    /// The row and column the next character goes to.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    // Moves the hardware cursor to the position, or off the screen while the
+    // scrollback is shown.
+    fn update_cursor(&self) {
+        if !self.visible {
+            return;
+        }
+        let position = if self.is_scrolled_back() {
+            BUFFER_HEIGHT * BUFFER_WIDTH
+        } else {
+            self.row_position * BUFFER_WIDTH + self.column_position.min(BUFFER_WIDTH - 1)
+        };
+        write_crtc(CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        write_crtc(CURSOR_LOCATION_LOW, position as u8);
+    }
+
+    pub fn show_cursor(&mut self) {
+        write_crtc(CURSOR_START, read_crtc(CURSOR_START) & !CURSOR_DISABLE);
+        self.update_cursor();
+    }
+
+    pub fn hide_cursor(&mut self) {
+        write_crtc(CURSOR_START, read_crtc(CURSOR_START) | CURSOR_DISABLE);
+    }
+
+    /// Changes the shape of the cursor, keeping whether it is shown.
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        // The upper bits of both registers hold other settings.
+        let start = read_crtc(CURSOR_START) & 0xe0 | (shape.start & 0x1f);
+        let end = read_crtc(CURSOR_END) & 0xe0 | (shape.end & 0x1f);
+        write_crtc(CURSOR_START, start);
+        write_crtc(CURSOR_END, end);
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -101,15 +455,235 @@ impl Writer {
         }
     }
 
+    /// Shows `lines` lines further back in the scrollback.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let scrollback = match self.scrollback.as_mut() {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+        if scrollback.offset == 0 {
+            for (row, line) in scrollback.live.iter_mut().enumerate() {
+                for (col, c) in line.iter_mut().enumerate() {
+                    *c = self.buffer.chars[row][col].read();
+                }
+            }
+        }
+        scrollback.offset = scrollback.offset.saturating_add(lines).min(scrollback.len);
+        self.show_scrollback();
+        self.update_cursor();
+    }
+
+    /// Shows `lines` lines further forward, back to the live view at most.
+    pub fn scroll_down(&mut self, lines: usize) {
+        let scrollback = match self.scrollback.as_mut() {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+        // At the live view already; `live` only holds the screen while
+        // scrolled up.
+        if scrollback.offset == 0 {
+            return;
+        }
+        scrollback.offset = scrollback.offset.saturating_sub(lines);
+        self.show_scrollback();
+        self.update_cursor();
+    }
+
+    /// Goes back to the live view if scrolled up; printing does this too.
+    pub fn restore_live_view(&mut self) {
+        if self.scrollback.as_ref().is_some_and(|s| s.offset > 0) {
+            self.scroll_down(usize::MAX);
+        }
+    }
+
+    pub fn is_scrolled_back(&self) -> bool {
+        self.scrollback.as_ref().is_some_and(|s| s.offset > 0)
+    }
+
+    /// Sets how many lines the scrollback keeps, up to `MAX_SCROLLBACK`.
+    /// The newest lines are kept when it shrinks.
+    pub fn set_scrollback_depth(&mut self, depth: usize) {
+        self.restore_live_view();
+        if let Some(scrollback) = self.scrollback.as_mut() {
+            scrollback.set_depth(depth);
+        }
+    }
+
+    fn show_scrollback(&mut self) {
+        let scrollback = match self.scrollback.as_ref() {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+        let first = scrollback.len - scrollback.offset;
+        let top = top_row();
+        for row in top..BUFFER_HEIGHT {
+            let line = scrollback.line(first + row - top, top);
+            for (col, c) in line.iter().enumerate() {
+                self.buffer.chars[row][col].write(*c);
+            }
+        }
+    }
+
+    /// Writes `s`, with characters outside ASCII translated to code page
+    /// 437 and ones it lacks shown as a single 0xfe each.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
+        for c in s.chars() {
+            if c.is_ascii() {
+                self.write_ascii(c as u8);
+            } else {
+                self.escape = Escape::None;
+                self.put_byte(to_cp437(c).unwrap_or(0xfe));
+            }
+        }
+        self.update_cursor();
+    }
+
+    // Writes an ASCII character, which may be part of an escape sequence.
+    fn write_ascii(&mut self, byte: u8) {
+        match self.escape {
+            Escape::None => match byte {
+                ESCAPE => self.escape = Escape::Started,
+                b'\r' => self.column_position = 0,
                 // Printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // Out of ascii range
-                _ => self.write_byte(0xfe),
+                0x20..=0x7e | b'\n' => self.put_byte(byte),
+                // Other control characters
+                _ => self.put_byte(0xfe),
+            },
+            Escape::Started => {
+                self.escape = match byte {
+                    b'[' => Escape::Csi {
+                        params: [0; MAX_ESCAPE_PARAMS],
+                        count: 0,
+                    },
+                    b'7' => {
+                        self.saved_position = self.position();
+                        Escape::None
+                    }
+                    b'8' => {
+                        let (row, column) = self.saved_position;
+                        self.set_position(row, column);
+                        Escape::None
+                    }
+                    _ => Escape::None,
+                }
+            }
+            Escape::Csi {
+                mut params,
+                mut count,
+            } => {
+                self.escape = Escape::None;
+                match byte {
+                    b'0'..=b'9' => {
+                        count = count.max(1);
+                        // Parameters past the first `MAX_ESCAPE_PARAMS` are
+                        // ignored.
+                        if let Some(param) = params.get_mut(count - 1) {
+                            *param = param
+                                .saturating_mul(10)
+                                .saturating_add(u16::from(byte - b'0'));
+                        }
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    b';' => {
+                        count = (count.max(1) + 1).min(MAX_ESCAPE_PARAMS + 1);
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    // Private mode markers like `?`, which change nothing here.
+                    0x3c..=0x3f => self.escape = Escape::Csi { params, count },
+                    0x40..=0x7e => {
+                        self.control_sequence(byte, &params[..count.min(MAX_ESCAPE_PARAMS)])
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Sets the colors of the text written from now on.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    fn control_sequence(&mut self, command: u8, params: &[u16]) {
+        // Missing and zero parameters take the default.
+        let param = |i: usize, default: usize| match params.get(i) {
+            Some(&n) if n != 0 => usize::from(n),
+            _ => default,
+        };
+        let (row, column) = self.position();
+        match command {
+            b'm' => self.select_graphic_rendition(params),
+            b'A' => self.set_position(row.saturating_sub(param(0, 1)), column),
+            b'B' => self.set_position(row + param(0, 1), column),
+            b'C' => self.set_position(row, column + param(0, 1)),
+            b'D' => self.set_position(row, column.saturating_sub(param(0, 1))),
+            b'G' => self.set_position(row, param(0, 1) - 1),
+            b'H' | b'f' => self.set_position(param(0, 1) - 1, param(1, 1) - 1),
+            b'J' => {
+                let cursor = row * BUFFER_WIDTH + column;
+                match params.first().copied().unwrap_or(0) {
+                    0 => self.clear_cells(cursor, BUFFER_HEIGHT * BUFFER_WIDTH),
+                    1 => self.clear_cells(0, cursor + 1),
+                    _ => self.clear_cells(0, BUFFER_HEIGHT * BUFFER_WIDTH),
+                }
+            }
+            b'K' => {
+                let start = row * BUFFER_WIDTH;
+                match params.first().copied().unwrap_or(0) {
+                    0 => self.clear_cells(start + column, start + BUFFER_WIDTH),
+                    1 => self.clear_cells(start, start + column + 1),
+                    _ => self.clear_cells(start, start + BUFFER_WIDTH),
+                }
+            }
+            b's' => self.saved_position = (row, column),
+            b'u' => {
+                let (row, column) = self.saved_position;
+                self.set_position(row, column);
+            }
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        let (mut foreground, mut background) = (self.color_code.0 & 0xf, self.color_code.0 >> 4);
+        // `ESC [ m` resets like `ESC [ 0 m`.
+        for &param in params.iter().chain(params.is_empty().then_some(&0)) {
+            match param {
+                0 => {
+                    self.bold = false;
+                    foreground = self.default_color.0 & 0xf;
+                    background = self.default_color.0 >> 4;
+                }
+                1 => {
+                    self.bold = true;
+                    foreground |= 8;
+                }
+                22 => {
+                    self.bold = false;
+                    foreground &= !8;
+                }
+                30..=37 => foreground = ansi_color(param - 30) | if self.bold { 8 } else { 0 },
+                39 => foreground = self.default_color.0 & 0xf,
+                40..=47 => background = ansi_color(param - 40),
+                49 => background = self.default_color.0 >> 4,
+                90..=97 => foreground = ansi_color(param - 90 + 8),
+                100..=107 => background = ansi_color(param - 100 + 8),
+                _ => {}
             }
         }
+        self.color_code = ColorCode(background << 4 | foreground);
+    }
+
+    // Blanks the cells from `start` to `end`, counted row by row.
+    fn clear_cells(&mut self, start: usize, end: usize) {
+        self.restore_live_view();
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for cell in start.max(top_row() * BUFFER_WIDTH)..end.min(BUFFER_HEIGHT * BUFFER_WIDTH) {
+            self.buffer.chars[cell / BUFFER_WIDTH][cell % BUFFER_WIDTH].write(blank);
+        }
     }
 }
 
@@ -122,11 +696,8 @@ impl fmt::Write for Writer {
 
 pub fn test_print() {
     use core::fmt::Write;
-    let mut writer = Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    };
+    let color_code = ColorCode::new(Color::Yellow, Color::Black);
+    let mut writer = Writer::new(color_code, vga_buffer(), true, None);
 
     writer.write_byte(b'H');
     writer.write_string("ello ");
@@ -135,10 +706,119 @@ pub fn test_print() {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+    /// The writer of the log console, which is shown first.
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(
+        ColorCode::new(Color::Yellow, Color::Black),
+        vga_buffer(),
+        true,
+        Some(unsafe { &mut *addr_of_mut!(SCROLLBACK) }),
+    ));
+    static ref SESSIONS: [Mutex<Writer>; CONSOLE_COUNT - 1] = core::array::from_fn(|i| {
+        let buffer = unsafe { backing_buffer(i + 1) };
+        Mutex::new(Writer::off_screen(
+            ColorCode::new(Color::LightGray, Color::Black),
+            buffer,
+        ))
+    });
+}
+
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(LOG_CONSOLE);
+static SWITCHING: Mutex<()> = Mutex::new(());
+// Whether the screen is taken by a graphics mode, with all consoles in their
+// backing buffers.
+static DETACHED: AtomicBool = AtomicBool::new(false);
+
+/// The writer of virtual console `index`.
+pub fn console(index: usize) -> &'static Mutex<Writer> {
+    match index {
+        LOG_CONSOLE => &WRITER,
+        _ => &SESSIONS[index - 1],
+    }
+}
+
+/// The console on the screen.
+pub fn active_console() -> usize {
+    ACTIVE_CONSOLE.load(Ordering::Relaxed)
+}
+
+// Runs `f` with the writer of console `index`, through `OUTPUT` for the log
+// console. Must be called with interrupts disabled.
+fn with_console<R>(index: usize, f: impl FnOnce(&mut Writer) -> R) -> R {
+    match index {
+        LOG_CONSOLE => OUTPUT.lock(&WRITER, f),
+        _ => f(&mut console(index).lock()),
+    }
+}
+
+/// Shows console `index`, as Alt+F1 and on do.
+pub fn switch_console(index: usize) {
+    assert!(index < CONSOLE_COUNT, "no console {}", index);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _switching = SWITCHING.lock();
+        let current = active_console();
+        if current == index {
+            return;
+        }
+        if DETACHED.load(Ordering::Relaxed) {
+            ACTIVE_CONSOLE.store(index, Ordering::Relaxed);
+            return;
+        }
+        // Always locked in the same order.
+        let (first, second) = (current.min(index), current.max(index));
+        with_console(first, |first_writer| {
+            with_console(second, |second_writer| {
+                let (old, new) = if current == first {
+                    (first_writer, second_writer)
+                } else {
+                    (second_writer, first_writer)
+                };
+                old.hide(unsafe { backing_buffer(current) });
+                new.show();
+            })
+        });
+        ACTIVE_CONSOLE.store(index, Ordering::Relaxed);
+    });
+}
+
+/// Moves the active console off the screen for a graphics mode to take it.
+/// Switching consoles only selects the one `attach_screen` shows.
+pub(crate) fn detach_screen() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _switching = SWITCHING.lock();
+        let _status = STATUS_LOCK.lock();
+        let active = active_console();
+        with_console(active, |writer| {
+            writer.hide(unsafe { backing_buffer(active) })
+        });
+        DETACHED.store(true, Ordering::Relaxed);
+    });
+}
+
+/// Puts the active console back on the screen, once it is in text mode
+/// again. The status bar stays blank until it is set next.
+pub(crate) fn attach_screen() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _switching = SWITCHING.lock();
+        let _status = STATUS_LOCK.lock();
+        DETACHED.store(false, Ordering::Relaxed);
+        with_console(active_console(), Writer::show);
+        if STATUS_BAR.load(Ordering::Relaxed) {
+            for col in 0..BUFFER_WIDTH {
+                vga_buffer().chars[0][col].write(ScreenChar {
+                    ascii_character: b' ',
+                    color_code: ColorCode::new(Color::Black, Color::LightGray),
+                });
+            }
+        }
+    });
+}
+
+/// Writes to console `index` whether or not it is shown.
+pub fn write_console(index: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        with_console(index, |writer| writer.write_fmt(args).unwrap());
     });
 }
 
@@ -155,11 +835,110 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
+    crate::dmesg::record(args);
+    write(args);
+}
+
+/// Prints `args` as `print!` does, but leaves it out of `dmesg`, for output
+/// recorded there already.
+pub fn write(args: fmt::Arguments) {
     use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+    interrupts::without_interrupts(|| OUTPUT.write(&WRITER, args, write_raw));
+    crate::graphics::_print(args);
+}
+
+/// Guards `WRITER` for `print!`; see `emergency`.
+pub static OUTPUT: OutputLock = OutputLock::new();
+
+// Writes white on red from the bottom of the screen, without the lock of
+// `WRITER` that a panicking CPU cannot get.
+fn write_raw(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let color_code = ColorCode::new(Color::White, Color::Red);
+    let _ = Writer::new(color_code, vga_buffer(), false, None).write_fmt(args);
+}
+
+/// Reserves the top row of the screen for `set_status`, or gives it back to
+/// the consoles, blank.
+pub fn set_status_bar(enabled: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _status = STATUS_LOCK.lock();
+        STATUS_BAR.store(enabled, Ordering::Relaxed);
+        let color_code = if enabled {
+            ColorCode::new(Color::Black, Color::LightGray)
+        } else {
+            ColorCode::new(Color::LightGray, Color::Black)
+        };
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        };
+        if !DETACHED.load(Ordering::Relaxed) {
+            for col in 0..BUFFER_WIDTH {
+                vga_buffer().chars[0][col].write(blank);
+            }
+        }
+    });
+}
+
+/// Shows `args` in the status bar, cut off at the width of the screen.
+pub fn set_status(args: fmt::Arguments) {
+    struct StatusRow(usize);
+
+    impl fmt::Write for StatusRow {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for c in s.chars() {
+                if self.0 == BUFFER_WIDTH {
+                    break;
+                }
+                let byte = if c.is_ascii() {
+                    c as u8
+                } else {
+                    to_cp437(c).unwrap_or(0xfe)
+                };
+                status_cell(self.0, byte);
+                self.0 += 1;
+            }
+            Ok(())
+        }
+    }
+
+    fn status_cell(col: usize, byte: u8) {
+        vga_buffer().chars[0][col].write(ScreenChar {
+            ascii_character: byte,
+            color_code: ColorCode::new(Color::Black, Color::LightGray),
+        });
+    }
+
+    use core::fmt::Write;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _status = STATUS_LOCK.lock();
+        if !STATUS_BAR.load(Ordering::Relaxed) || DETACHED.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut row = StatusRow(0);
+        let _ = row.write_fmt(args);
+        for col in row.0..BUFFER_WIDTH {
+            status_cell(col, b' ');
+        }
+    });
+}
+
+/// Scrolls the console on the screen back by `lines`, as Shift+PageUp does.
+/// Only the log console keeps a scrollback.
+pub fn scroll_up(lines: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        with_console(active_console(), |writer| writer.scroll_up(lines))
+    });
+}
+
+/// Scrolls the console on the screen forward by `lines`, as Shift+PageDown
+/// does.
+pub fn scroll_down(lines: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        with_console(active_console(), |writer| writer.scroll_down(lines))
     });
 }
 
@@ -190,3 +969,190 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\nscrolled away").unwrap();
+        for _ in 0..BUFFER_HEIGHT {
+            writeln!(writer).unwrap();
+        }
+        let row = |writer: &Writer, row: usize| writer.buffer.chars[row][0].read().ascii_character;
+
+        writer.scroll_up(2);
+        assert!(writer.is_scrolled_back());
+        assert_eq!(row(&writer, 0), b's');
+        writer.scroll_down(2);
+        assert!(!writer.is_scrolled_back());
+        assert_eq!(row(&writer, 0), b' ');
+
+        // New output goes to the live view.
+        writer.scroll_up(1);
+        write!(writer, "live").unwrap();
+        assert!(!writer.is_scrolled_back());
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), b'l');
+
+        // Scrolling down at the live view leaves the screen alone.
+        writer.scroll_down(1);
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), b'l');
+
+        // As far back as there is history, however far asked.
+        writer.scroll_up(usize::MAX);
+        assert!(writer.is_scrolled_back());
+        writer.scroll_up(usize::MAX);
+        writer.restore_live_view();
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), b'l');
+    });
+}
+
+#[test_case]
+fn test_cursor_position() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let cursor = || {
+        usize::from(read_crtc(CURSOR_LOCATION_HIGH)) << 8
+            | usize::from(read_crtc(CURSOR_LOCATION_LOW))
+    };
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer).unwrap();
+        write!(writer, "abc").unwrap();
+        assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, 3));
+        assert_eq!(cursor(), (BUFFER_HEIGHT - 1) * BUFFER_WIDTH + 3);
+
+        writer.set_position(2, 10);
+        write!(writer, "x").unwrap();
+        assert_eq!(writer.buffer.chars[2][10].read().ascii_character, b'x');
+        assert_eq!(cursor(), 2 * BUFFER_WIDTH + 11);
+
+        writer.set_cursor_shape(CursorShape::BLOCK);
+        assert_eq!(read_crtc(CURSOR_START) & 0x1f, 0);
+        writer.hide_cursor();
+        assert_ne!(read_crtc(CURSOR_START) & CURSOR_DISABLE, 0);
+        writer.show_cursor();
+        assert_eq!(read_crtc(CURSOR_START) & CURSOR_DISABLE, 0);
+        writer.set_cursor_shape(CursorShape::UNDERLINE);
+        writer.set_position(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\n\x1b[31;44mr\x1b[1;32mg\x1b[0md").unwrap();
+        let cell = |col: usize| writer.buffer.chars[BUFFER_HEIGHT - 1][col].read();
+        assert_eq!(cell(0).color_code, ColorCode::new(Color::Red, Color::Blue));
+        assert_eq!(
+            cell(1).color_code,
+            ColorCode::new(Color::LightGreen, Color::Blue)
+        );
+        assert_eq!(
+            cell(2).color_code,
+            ColorCode::new(Color::Yellow, Color::Black)
+        );
+        assert_eq!(cell(2).ascii_character, b'd');
+    });
+}
+
+#[test_case]
+fn test_ansi_extra_params_ignored() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\n\x1b[0;0;0;31;44mx\x1b[0m").unwrap();
+        let cell = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        assert_eq!(cell.color_code, ColorCode::new(Color::Red, Color::Black));
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor_and_clear() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\n0123456789\x1b[4D\x1b[K").unwrap();
+        assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, 6));
+        let char_at = |writer: &Writer, row: usize, col: usize| {
+            writer.buffer.chars[row][col].read().ascii_character
+        };
+        assert_eq!(char_at(&writer, BUFFER_HEIGHT - 1, 5), b'5');
+        assert_eq!(char_at(&writer, BUFFER_HEIGHT - 1, 6), b' ');
+
+        write!(writer, "\x1b[s\x1b[3;5Hx\x1b[u").unwrap();
+        assert_eq!(char_at(&writer, 2, 4), b'x');
+        assert_eq!(writer.position(), (BUFFER_HEIGHT - 1, 6));
+
+        write!(writer, "\x1b[2J").unwrap();
+        assert_eq!(char_at(&writer, 2, 4), b' ');
+        assert_eq!(char_at(&writer, BUFFER_HEIGHT - 1, 0), b' ');
+    });
+}
+
+#[test_case]
+fn test_cp437() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nWörld ░☺β😀!").unwrap();
+        let row = BUFFER_HEIGHT - 1;
+        let expected = [
+            b'W', 0x94, b'r', b'l', b'd', b' ', 0xb0, 0x01, 0xe1, 0xfe, b'!',
+        ];
+        for (col, &byte) in expected.iter().enumerate() {
+            assert_eq!(writer.buffer.chars[row][col].read().ascii_character, byte);
+        }
+    });
+}
+
+#[test_case]
+fn test_virtual_consoles() {
+    let screen = |row: usize| vga_buffer().chars[row][0].read().ascii_character;
+
+    println!();
+    switch_console(2);
+    assert_eq!(active_console(), 2);
+    write_console(2, format_args!("\nsession"));
+    assert_eq!(screen(BUFFER_HEIGHT - 1), b's');
+    // The log console keeps writing off-screen.
+    println!("log");
+    assert_eq!(screen(BUFFER_HEIGHT - 2), b' ');
+
+    switch_console(LOG_CONSOLE);
+    assert_eq!(screen(BUFFER_HEIGHT - 2), b'l');
+    switch_console(2);
+    assert_eq!(screen(BUFFER_HEIGHT - 1), b's');
+    switch_console(LOG_CONSOLE);
+}
+
+#[test_case]
+fn test_status_bar() {
+    let screen = |row: usize| vga_buffer().chars[row][0].read().ascii_character;
+
+    set_status_bar(true);
+    set_status(format_args!("status {}", 1));
+    for _ in 0..BUFFER_HEIGHT {
+        println!("x");
+    }
+    assert_eq!(screen(0), b's');
+    assert_eq!(screen(1), b'x');
+    print!("\x1b[1;1Hy\x1b[2J");
+    assert_eq!(screen(0), b's');
+    assert_eq!(screen(1), b' ');
+    set_status_bar(false);
+    assert_eq!(screen(0), b' ');
+}
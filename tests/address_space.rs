@@ -0,0 +1,174 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::address_space::{self, AddressSpace};
+use rustos::memory::{self, BootInfoFrameAllocator};
+use rustos::user::{self, Exit, USER_SPACE_START};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const DATA: u64 = USER_SPACE_START;
+const CODE: u64 = USER_SPACE_START + 0x1000;
+const STACK_END: u64 = USER_SPACE_START + 0x10_0000;
+
+// exit(42)
+const EXIT_PROGRAM: &[u8] = &[
+    0xbf, 0x2a, 0x00, 0x00, 0x00, // mov edi, 42
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x0f, 0x05, // syscall
+];
+
+// Writes 9 to DATA and exits with what it reads back.
+const WRITE_DATA_PROGRAM: &[u8] = &[
+    0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, // mov rax, DATA
+    0x48, 0xc7, 0x00, 0x09, 0x00, 0x00, 0x00, // mov qword ptr [rax], 9
+    0x48, 0x8b, 0x38, // mov rdi, [rax]
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x0f, 0x05, // syscall
+];
+
+fn new_space(frame_allocator: &mut BootInfoFrameAllocator) -> AddressSpace {
+    let mut space = AddressSpace::new(frame_allocator).unwrap();
+    let data = Page::containing_address(VirtAddr::new(DATA));
+    space
+        .map_user_pages(data, 1, PageTableFlags::WRITABLE, frame_allocator)
+        .unwrap();
+    space
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let (mapper, mut frame_allocator) = rustos::test_boot(boot_info);
+    let kernel = memory::kernel_level_4_frame();
+
+    serial_print!("address_space::isolated...\t");
+    let a = new_space(&mut frame_allocator);
+    let b = new_space(&mut frame_allocator);
+    let data = DATA as *mut u64;
+    unsafe {
+        a.switch();
+        data.write_volatile(1);
+        b.switch();
+        assert_eq!(data.read_volatile(), 0);
+        data.write_volatile(2);
+        a.switch();
+        assert!(a.is_active());
+        assert_eq!(data.read_volatile(), 1);
+    }
+    // Each address space has a user heap of its own.
+    assert_eq!(
+        address_space::reserve_heap(0x1000),
+        Some(address_space::USER_HEAP_START)
+    );
+    unsafe { b.switch() };
+    assert_eq!(
+        address_space::reserve_heap(0x1000),
+        Some(address_space::USER_HEAP_START)
+    );
+    unsafe { a.switch() };
+    // The kernel half, including the heap, is still there.
+    let boxed = Box::new(7);
+    assert_eq!(*boxed, 7);
+    serial_println!("[ok]");
+
+    serial_print!("address_space::free...\t");
+    let level_4_frame = a.level_4_frame();
+    a.free(&mut frame_allocator);
+    assert_eq!(Cr3::read().0, kernel);
+    assert_eq!(frame_allocator.allocate_frame(), Some(level_4_frame));
+    b.free(&mut frame_allocator);
+    serial_println!("[ok]");
+
+    serial_print!("address_space::run_user_code...\t");
+    let mut space = AddressSpace::new(&mut frame_allocator).unwrap();
+    let code = Page::containing_address(VirtAddr::new(CODE));
+    let stack = Page::containing_address(VirtAddr::new(STACK_END - 1));
+    space
+        .map_user_pages(code, 1, PageTableFlags::WRITABLE, &mut frame_allocator)
+        .unwrap();
+    space
+        .map_user_pages(stack, 1, PageTableFlags::WRITABLE, &mut frame_allocator)
+        .unwrap();
+    let exit = unsafe {
+        space.switch();
+        core::ptr::copy_nonoverlapping(EXIT_PROGRAM.as_ptr(), CODE as *mut u8, EXIT_PROGRAM.len());
+        user::run(VirtAddr::new(CODE), VirtAddr::new(STACK_END))
+    };
+    assert_eq!(exit, Exit::Exited(42));
+    serial_println!("[ok]");
+
+    serial_print!("address_space::drop...\t");
+    memory::set_global(mapper, frame_allocator);
+    let level_4_frame = space.level_4_frame();
+    drop(space);
+    assert_eq!(Cr3::read().0, kernel);
+    let reused = memory::with_global(|_, frame_allocator| frame_allocator.allocate_frame());
+    assert_eq!(reused, Some(Some(level_4_frame)));
+    assert_eq!(
+        memory::with_global(|_, _| memory::in_with_global()),
+        Some(true)
+    );
+    assert!(!memory::in_with_global());
+    serial_println!("[ok]");
+
+    serial_print!("address_space::fork...\t");
+    let mut parent = memory::with_global(|_, frame_allocator| {
+        let mut space = new_space(frame_allocator);
+        for page in [code, stack].iter() {
+            space
+                .map_user_pages(*page, 1, PageTableFlags::WRITABLE, frame_allocator)
+                .unwrap();
+        }
+        space
+    })
+    .unwrap();
+    unsafe {
+        parent.switch();
+        data.write_volatile(5);
+        let program = WRITE_DATA_PROGRAM;
+        core::ptr::copy_nonoverlapping(program.as_ptr(), CODE as *mut u8, program.len());
+    }
+    let mut child = memory::with_global(|_, frame_allocator| parent.fork(frame_allocator))
+        .unwrap()
+        .unwrap();
+    let data_page = Page::containing_address(VirtAddr::new(DATA));
+    let shared = parent.mapper().translate_page(data_page).unwrap();
+    assert_eq!(address_space::mapping_count(shared), 2);
+
+    // The parent gets a copy on its first write.
+    unsafe { data.write_volatile(6) };
+    assert_eq!(address_space::mapping_count(shared), 1);
+    assert_ne!(parent.mapper().translate_page(data_page).ok(), Some(shared));
+
+    // The child, now the only one left mapping the frame, keeps it.
+    let exit = unsafe {
+        child.switch();
+        assert_eq!(data.read_volatile(), 5);
+        user::run(VirtAddr::new(CODE), VirtAddr::new(STACK_END))
+    };
+    assert_eq!(exit, Exit::Exited(9));
+    assert_eq!(child.mapper().translate_page(data_page).ok(), Some(shared));
+    unsafe {
+        parent.switch();
+        assert_eq!(data.read_volatile(), 6);
+    }
+    drop(child);
+    drop(parent);
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    rustos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
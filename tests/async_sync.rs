@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::async_task::executor::Executor;
+use rustos::async_task::sync::{broadcast, mpsc, oneshot, AsyncMutex, Semaphore};
+use rustos::async_task::{self, JoinHandle};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    let mut executor = Executor::new();
+    executor.spawn(run_all());
+    executor.run();
+}
+
+async fn run_all() {
+    serial_print!("async_sync::mpsc...\t");
+    mpsc_bounded().await;
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::oneshot...\t");
+    oneshot_roundtrip().await;
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::broadcast...\t");
+    broadcast_lagging().await;
+    serial_println!("[ok]");
+
+    serial_print!("async_sync::mutex_and_semaphore...\t");
+    mutex_and_semaphore().await;
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+async fn mpsc_bounded() {
+    let (tx, mut rx) = mpsc::channel(2);
+    let producers: [JoinHandle<()>; 2] = [0, 1].map(|id| {
+        let tx = tx.clone();
+        async_task::spawn(async move {
+            for i in 0..10 {
+                tx.send(id * 100 + i).await.unwrap();
+            }
+        })
+    });
+    drop(tx);
+
+    let mut sum = 0;
+    let mut count = 0;
+    while let Some(value) = rx.recv().await {
+        sum += value;
+        count += 1;
+    }
+    assert_eq!(count, 20);
+    assert_eq!(sum, 2 * 45 + 100 * 10);
+    for producer in producers {
+        producer.await.unwrap();
+    }
+}
+
+async fn oneshot_roundtrip() {
+    let (tx, rx) = oneshot::channel();
+    async_task::spawn(async move { tx.send(5).unwrap() });
+    assert_eq!(rx.await, Ok(5));
+
+    let (tx, rx) = oneshot::channel::<u8>();
+    drop(tx);
+    assert_eq!(rx.await, Err(oneshot::RecvError));
+}
+
+async fn broadcast_lagging() {
+    let (tx, mut rx) = broadcast::channel(2);
+    let mut late = tx.subscribe();
+    for i in 0..3 {
+        tx.send(i);
+    }
+    assert_eq!(rx.recv().await, Err(broadcast::RecvError::Lagged(1)));
+    assert_eq!(rx.recv().await, Ok(1));
+    assert_eq!(rx.recv().await, Ok(2));
+    assert_eq!(late.recv().await, Err(broadcast::RecvError::Lagged(1)));
+    drop(tx);
+    assert_eq!(rx.recv().await, Err(broadcast::RecvError::Closed));
+}
+
+async fn mutex_and_semaphore() {
+    let counter = Arc::new(AsyncMutex::new(0));
+    let workers: [JoinHandle<()>; 4] = [(); 4].map(|_| {
+        let counter = counter.clone();
+        async_task::spawn(async move {
+            for _ in 0..10 {
+                let mut guard = counter.lock().await;
+                let value = *guard;
+                // Yield while holding the lock; other workers must wait.
+                async_task::spawn(async {}).await.unwrap();
+                *guard = value + 1;
+            }
+        })
+    });
+    for worker in workers {
+        worker.await.unwrap();
+    }
+    assert_eq!(*counter.lock().await, 40);
+
+    let semaphore = Semaphore::new(2);
+    let first = semaphore.acquire().await;
+    let _second = semaphore.acquire().await;
+    assert!(semaphore.try_acquire().is_none());
+    drop(first);
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,162 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::elf::{self, ElfError, ElfFile, PF_X, PT_LOAD};
+use rustos::user::{Exit, USER_SPACE_START};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const HEADERS_SIZE: u64 = 64 + 56;
+
+// Exits with argc plus the first byte of argv[1].
+const ARGS_PROGRAM: &[u8] = &[
+    0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp]
+    0x48, 0x8b, 0x44, 0x24, 0x10, // mov rax, [rsp + 16]
+    0x0f, 0xb6, 0x00, // movzx eax, byte ptr [rax]
+    0x48, 0x01, 0xc7, // add rdi, rax
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x0f, 0x05, // syscall
+];
+
+// Writes to its own code, which is mapped read-only.
+const WRITE_CODE_PROGRAM: &[u8] = &[
+    0x48, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00, // lea rax, [rip]
+    0xc6, 0x00, 0x00, // mov byte ptr [rax], 0
+    0x0f, 0x0b, // ud2
+];
+
+// An executable with the headers and `code` in a single read-only,
+// executable segment at `base`.
+fn build_elf(base: u64, code: &[u8]) -> Vec<u8> {
+    let size = HEADERS_SIZE + code.len() as u64;
+    let mut elf = Vec::new();
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes());
+    elf.extend_from_slice(&0x3eu16.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&(base + HEADERS_SIZE).to_le_bytes());
+    elf.extend_from_slice(&64u64.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    for field in [64u16, 56, 1, 64, 0, 0].iter() {
+        elf.extend_from_slice(&field.to_le_bytes());
+    }
+
+    elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&(PF_X | 4).to_le_bytes());
+    for field in [0, base, base, size, size, 0x1000].iter() {
+        elf.extend_from_slice(&field.to_le_bytes());
+    }
+    elf.extend_from_slice(code);
+    elf
+}
+
+// `image` with a second, empty `PT_LOAD` segment of `size` bytes at `vaddr`,
+// with both program headers moved to the end.
+fn add_segment(mut image: Vec<u8>, vaddr: u64, size: u64) -> Vec<u8> {
+    let program_headers = image.len() as u64;
+    let first = image[64..64 + 56].to_vec();
+    image.extend_from_slice(&first);
+    image.extend_from_slice(&PT_LOAD.to_le_bytes());
+    image.extend_from_slice(&4u32.to_le_bytes());
+    for field in [0, vaddr, vaddr, 0, size, 0x1000].iter() {
+        image.extend_from_slice(&field.to_le_bytes());
+    }
+    image[32..40].copy_from_slice(&program_headers.to_le_bytes());
+    image[56..58].copy_from_slice(&2u16.to_le_bytes());
+    image
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let (mut mapper, mut frame_allocator) = rustos::test_boot(boot_info);
+
+    serial_print!("elf_loader::rejects_invalid...\t");
+    let mut image = build_elf(USER_SPACE_START, ARGS_PROGRAM);
+    assert!(matches!(
+        ElfFile::parse(&image[..32]),
+        Err(ElfError::Truncated)
+    ));
+    image[18] = 0x03;
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::Unsupported)));
+    image[0] = 0;
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::BadMagic)));
+    let kernel = build_elf(0x4444_4444_0000, ARGS_PROGRAM);
+    let kernel = ElfFile::parse(&kernel).unwrap();
+    assert!(matches!(
+        elf::load(&kernel, &[], &[], &mut mapper, &mut frame_allocator),
+        Err(ElfError::BadSegment)
+    ));
+    let mut image = build_elf(USER_SPACE_START, ARGS_PROGRAM);
+    image[24..32].copy_from_slice(&(USER_SPACE_START + 0x10_0000).to_le_bytes());
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::BadEntry)));
+    image[24..32].copy_from_slice(&0x8000_0000_0000u64.to_le_bytes());
+    assert!(matches!(ElfFile::parse(&image), Err(ElfError::BadEntry)));
+    serial_println!("[ok]");
+
+    serial_print!("elf_loader::arguments_too_large...\t");
+    let image = build_elf(USER_SPACE_START, ARGS_PROGRAM);
+    let elf = ElfFile::parse(&image).unwrap();
+    // More than leaves the program its share of the stack, without taking
+    // it from the small heap
+    let page = [b'x'; 4096];
+    let argv = [core::str::from_utf8(&page).unwrap(); elf::STACK_PAGES as usize - 1];
+    assert!(matches!(
+        elf::load(&elf, &argv, &[], &mut mapper, &mut frame_allocator),
+        Err(ElfError::ArgumentsTooLarge)
+    ));
+    serial_println!("[ok]");
+
+    serial_print!("elf_loader::unmaps_on_failure...\t");
+    let base = USER_SPACE_START + 0x20_0000;
+    let image = add_segment(build_elf(base, ARGS_PROGRAM), 0x4444_4444_0000, 0x1000);
+    let elf = ElfFile::parse(&image).unwrap();
+    assert!(matches!(
+        elf::load(&elf, &[], &[], &mut mapper, &mut frame_allocator),
+        Err(ElfError::BadSegment)
+    ));
+    assert!(mapper.translate_addr(VirtAddr::new(base)).is_none());
+    serial_println!("[ok]");
+
+    serial_print!("elf_loader::arguments...\t");
+    let image = build_elf(USER_SPACE_START, ARGS_PROGRAM);
+    let elf = ElfFile::parse(&image).unwrap();
+    let program = elf::load(
+        &elf,
+        &["prog", "A"],
+        &["HOME=/"],
+        &mut mapper,
+        &mut frame_allocator,
+    )
+    .unwrap();
+    assert_eq!(program.stack_pointer.as_u64() % 16, 0);
+    assert_eq!(unsafe { program.run() }, Exit::Exited(2 + u64::from(b'A')));
+    serial_println!("[ok]");
+
+    serial_print!("elf_loader::read_only_code...\t");
+    let base = USER_SPACE_START + 0x10_0000;
+    let image = build_elf(base, WRITE_CODE_PROGRAM);
+    let elf = ElfFile::parse(&image).unwrap();
+    let program = elf::load(&elf, &["prog"], &[], &mut mapper, &mut frame_allocator).unwrap();
+    match unsafe { program.run() } {
+        Exit::PageFault { address, .. } => assert_eq!(address, base + HEADERS_SIZE + 7),
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    rustos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use rustos::async_task::executor::Executor;
+use rustos::async_task::{self, Spawner};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    serial_print!("executor_capacity::backpressure...\t");
+
+    let mut executor = Executor::with_capacity(3);
+    let spawner = executor.spawner();
+    executor.spawn(check(spawner));
+    executor.run();
+}
+
+// Wakes itself many times before completing.
+struct YieldTimes(usize);
+
+impl Future for YieldTimes {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 == 0 {
+            return Poll::Ready(());
+        }
+        self.0 -= 1;
+        cx.waker().wake_by_ref();
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+async fn check(spawner: Spawner) {
+    let stuck = spawner
+        .try_spawn(core::future::pending::<()>())
+        .unwrap_or_else(|_| panic!("below capacity"));
+    let _busy = spawner
+        .try_spawn(YieldTimes(1000))
+        .unwrap_or_else(|_| panic!("below capacity"));
+    assert!(spawner.try_spawn(async {}).is_err());
+
+    stuck.abort();
+    let late = spawner.spawn_when_ready(async { 7 }).await;
+    assert_eq!(late.await, Ok(7));
+
+    // Plain spawns ignore the capacity and are no longer limited by the
+    // size of the ready queue.
+    let handles: Vec<_> = (0..150)
+        .map(|i| async_task::spawn(async move { i }))
+        .collect();
+    let mut sum = 0;
+    for handle in handles {
+        sum += handle.await.unwrap();
+    }
+    assert_eq!(sum, 149 * 150 / 2);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
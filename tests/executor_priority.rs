@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use rustos::async_task::executor::Executor;
+use rustos::async_task::{Builder, Priority};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    serial_print!("executor_priority::classes_and_budget...\t");
+
+    let mut executor = Executor::new();
+    executor.set_poll_budget(2);
+    let spawner = executor.spawner();
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for &priority in &[
+        Priority::Background,
+        Priority::Interactive,
+        Priority::BottomHalf,
+    ] {
+        let order = order.clone();
+        Builder::new()
+            .priority(priority)
+            .spawn_on(&spawner, async move { order.lock().push(priority) });
+    }
+    Builder::new()
+        .priority(Priority::BottomHalf)
+        .spawn_on(&spawner, Yield(usize::MAX));
+    Builder::new()
+        .priority(Priority::Background)
+        .spawn_on(&spawner, check(order));
+    executor.run();
+}
+
+// Wakes itself `n` times before completing.
+struct Yield(usize);
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 == 0 {
+            return Poll::Ready(());
+        }
+        self.0 -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// Runs at the lowest priority next to a bottom-half task that never stops
+// waking itself, so it only completes if the poll budget lets it through.
+async fn check(order: Arc<Mutex<Vec<Priority>>>) {
+    Yield(10).await;
+    assert_eq!(
+        *order.lock(),
+        [
+            Priority::BottomHalf,
+            Priority::Interactive,
+            Priority::Background
+        ]
+    );
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
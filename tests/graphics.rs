@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::convert::TryInto;
+use core::panic::PanicInfo;
+use rustos::graphics::{self, Canvas, TextRenderer, GLYPH_HEIGHT, GLYPH_WIDTH, HEIGHT, WIDTH};
+use rustos::memory;
+use rustos::vga_buffer::WRITER;
+use rustos::{exit_qemu, println, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Reads the framebuffer itself rather than going through a canvas.
+fn framebuffer(x: usize, y: usize) -> u8 {
+    let base = memory::physical_memory_offset() + 0xa0000u64;
+    unsafe { base.as_ptr::<u8>().add(y * WIDTH + x).read_volatile() }
+}
+
+fn text_cell(row: usize, column: usize) -> u8 {
+    unsafe {
+        (0xb8000 as *const u8)
+            .add((row * 80 + column) * 2)
+            .read_volatile()
+    }
+}
+
+fn assert_glyph(x: usize, y: usize, byte: u8, foreground: u8, background: u8) {
+    for (row, &bits) in graphics::glyph(byte).iter().enumerate() {
+        for column in 0..GLYPH_WIDTH {
+            let set = bits & (0x80 >> column) != 0;
+            let expected = if set { foreground } else { background };
+            assert_eq!(framebuffer(x + column, y + row), expected);
+        }
+    }
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+
+    serial_print!("graphics::primitives...\t");
+    let mut pixels = [0u8; 16 * 8];
+    let mut canvas = Canvas::new(&mut pixels, 16);
+    assert_eq!((canvas.width(), canvas.height()), (16, 8));
+    canvas.set_pixel(-1, 0, 1);
+    canvas.set_pixel(16, 0, 1);
+    assert!(canvas.pixel(16, 0).is_none());
+    canvas.line((0, 0), (7, 7), 2);
+    assert!((0..8).all(|i| canvas.pixel(i, i) == Some(2)));
+    assert_eq!(canvas.pixel(1, 0), Some(0));
+    canvas.rect(8, 0, 4, 3, 3);
+    assert_eq!(canvas.pixel(11, 2), Some(3));
+    assert_eq!(canvas.pixel(9, 1), Some(0));
+    canvas.fill_rect(14, 6, 10, 10, 4);
+    assert_eq!(canvas.pixel(15, 7), Some(4));
+    assert_eq!(canvas.pixel(13, 7), Some(0));
+    canvas.blit(-1, 6, &[5, 6, 7, 8], 2);
+    assert_eq!((canvas.pixel(0, 6), canvas.pixel(0, 7)), (Some(6), Some(8)));
+    canvas.scroll_up(6, 9);
+    assert_eq!(canvas.pixel(0, 0), Some(6));
+    assert_eq!(canvas.pixel(15, 2), Some(9));
+    canvas.blit(0, 0, &[1, 2], 0);
+    assert_eq!(canvas.pixel(0, 0), Some(6));
+    let mut empty = Canvas::new(&mut pixels, 0);
+    assert_eq!((empty.width(), empty.height()), (0, 0));
+    empty.fill_rect(0, 0, 4, 4, 1);
+    assert!(empty.pixel(0, 0).is_none());
+    serial_println!("[ok]");
+
+    serial_print!("graphics::mode_13h...\t");
+    println!("graphics test");
+    graphics::enter_mode_13h();
+    assert!(graphics::is_active());
+    assert!(graphics::glyph(b'A').iter().any(|&bits| bits != 0));
+    assert_eq!(framebuffer(0, 0), 0);
+    graphics::with_screen(|screen| {
+        assert_eq!((screen.width(), screen.height()), (WIDTH, HEIGHT));
+        screen.set_pixel(319, 199, 15);
+        screen.line((10, 10), (20, 10), 4);
+        screen.blit(100, 50, &[1, 2, 3, 4], 2);
+    })
+    .unwrap();
+    assert_eq!(framebuffer(319, 199), 15);
+    assert!((10..=20).all(|x| framebuffer(x, 10) == 4));
+    assert_eq!((framebuffer(101, 50), framebuffer(100, 51)), (2, 3));
+    graphics::set_color(200, 0xff, 0x80, 0);
+    assert_eq!(graphics::rgb(0xff, 0xff, 0xff), 231);
+    serial_println!("[ok]");
+
+    serial_print!("graphics::text...\t");
+    println!("Hi");
+    assert_glyph(0, 0, b'H', 7, 0);
+    assert_glyph(GLYPH_WIDTH, 0, b'i', 7, 0);
+    let mut pixels = [0u8; 16 * GLYPH_HEIGHT];
+    let mut canvas = Canvas::new(&mut pixels, 16);
+    let mut text = TextRenderer::new(1, 2);
+    text.write_str(&mut canvas, "\x1b[31mab");
+    assert_eq!(text.position(), (2, 0));
+    text.write_str(&mut canvas, "c");
+    assert_eq!(text.position(), (1, 0));
+    // Escapes without a control sequence end after one byte.
+    let mut text = TextRenderer::new(1, 2);
+    text.write_str(&mut canvas, "\x1b7d\x1b8e");
+    assert_eq!(text.position(), (2, 0));
+    serial_println!("[ok]");
+
+    serial_print!("graphics::leave...\t");
+    let font: [u8; GLYPH_HEIGHT] = graphics::glyph(b'A').try_into().unwrap();
+    graphics::leave_mode_13h();
+    assert!(!graphics::is_active());
+    assert!(graphics::with_screen(|_| ()).is_none());
+    // What was printed meanwhile is on the text console.
+    let (row, _) = WRITER.lock().position();
+    assert_eq!(text_cell(row - 1, 0), b'H');
+    assert_eq!(text_cell(row - 2, 0), b'g');
+    // The font survived mode 13h.
+    graphics::enter_mode_13h();
+    graphics::leave_mode_13h();
+    assert_eq!(graphics::glyph(b'A'), &font[..]);
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    rustos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
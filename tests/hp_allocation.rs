@@ -15,15 +15,7 @@ use core::panic::PanicInfo;
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use rustos::allocator;
-    use rustos::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    rustos::init();
-    let mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    rustos::test_boot(boot_info);
 
     test_main();
     loop {}
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::async_task::executor::Executor;
+use rustos::async_task::JoinError;
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    serial_print!("join_handle::join_and_abort...\t");
+
+    let mut executor = Executor::new();
+    let answer = executor.spawn(async { 42 });
+    let stuck = executor.spawn(core::future::pending::<()>());
+    stuck.abort();
+    executor.spawn(async {}).detach();
+    executor.spawn(async move {
+        assert_eq!(answer.await, Ok(42));
+        assert_eq!(stuck.await, Err(JoinError::Cancelled));
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    });
+    executor.run();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, Ordering};
+use rustos::async_task::executor::{Executor, Spawner};
+use rustos::{apic, exit_qemu, serial_print, serial_println, smp, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Bit n is set once a worker ran on CPU n.
+static CPUS_USED: AtomicU32 = AtomicU32::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let (mut mapper, mut frame_allocator) = rustos::test_boot(boot_info);
+
+    serial_print!("smp::work_stealing...\t");
+
+    // `cargo test-smp` starts QEMU with `-smp 4`.
+    let mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let cpus = smp::init(mem_offset, &mut mapper, &mut frame_allocator).expect("SMP init failed");
+    assert_eq!(cpus, 4);
+
+    let mut executor = Executor::new();
+    executor.spawn(check(executor.spawner()));
+    executor.run();
+}
+
+async fn check(spawner: Spawner) {
+    // Give the other CPUs time to start their executors.
+    apic::delay_us(10_000);
+
+    let workers: Vec<_> = (0..16)
+        .map(|_| {
+            spawner.spawn(async {
+                CPUS_USED.fetch_or(1 << smp::cpu_index(), Ordering::SeqCst);
+                apic::delay_us(1_000);
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.await.unwrap();
+    }
+
+    assert!(CPUS_USED.load(Ordering::SeqCst).count_ones() > 1);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
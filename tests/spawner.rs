@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::async_task::executor::Executor;
+use rustos::async_task::{self, Spawner};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    serial_print!("spawner::spawn_from_task...\t");
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(parent(spawner));
+    executor.run();
+}
+
+async fn parent(spawner: Spawner) {
+    let child = spawner.spawn(async { 1 });
+    let global = async_task::spawn(async { 2 });
+    let nested = spawner
+        .clone()
+        .spawn(async { async_task::spawn(async { 3 }).await });
+
+    assert_eq!(child.await, Ok(1));
+    assert_eq!(global.await, Ok(2));
+    assert_eq!(nested.await, Ok(Ok(3)));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, Ordering};
+use rustos::async_task::executor::Executor;
+use rustos::async_task::{self, Builder, JoinError, RestartPolicy, Spawner, TaskState};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+static SERVICE_STARTS: AtomicU32 = AtomicU32::new(0);
+static DROPS: AtomicU32 = AtomicU32::new(0);
+
+struct Owned(Box<[u64; 4]>, Vec<u8>);
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    serial_print!("supervisor::panic_isolation...\t");
+
+    let mut executor = Executor::new();
+    executor.set_supervised(true);
+    let spawner = executor.spawner();
+    executor.spawn(check(spawner));
+    executor.run();
+}
+
+async fn failing_service() {
+    SERVICE_STARTS.fetch_add(1, Ordering::SeqCst);
+    panic!("service failure");
+}
+
+async fn check(spawner: Spawner) {
+    let failing = Builder::new()
+        .name("failing")
+        .spawn(async { panic!("task failure") });
+    let id = failing.id();
+    assert_eq!(failing.await, Err(JoinError::Panicked));
+    let tasks = async_task::tasks();
+    let info = tasks.iter().find(|t| t.id == id).unwrap();
+    assert_eq!(info.state, TaskState::Failed);
+
+    // What a future that panicked mid-poll owns is leaked, not dropped.
+    let owning = async_task::spawn(async {
+        let owned = Owned(Box::new([1; 4]), vec![2; 16]);
+        async_task::spawn(async {}).await.unwrap();
+        assert_eq!(owned.1.len() as u64, owned.0[0], "panic with owned state");
+    });
+    assert_eq!(owning.await, Err(JoinError::Panicked));
+    assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+    Builder::new()
+        .name("service")
+        .restart(RestartPolicy::Limited(2))
+        .spawn_service(&spawner, failing_service);
+    while SERVICE_STARTS.load(Ordering::SeqCst) < 3 {
+        async_task::spawn(async {}).await.unwrap();
+    }
+    // Give the executor the chance to exceed the restart limit.
+    for _ in 0..5 {
+        async_task::spawn(async {}).await.unwrap();
+    }
+    assert_eq!(SERVICE_STARTS.load(Ordering::SeqCst), 3);
+    assert!(async_task::tasks()
+        .iter()
+        .filter(|t| t.name.as_deref() == Some("service"))
+        .all(|t| t.state == TaskState::Failed));
+
+    // The executor keeps running other tasks.
+    assert_eq!(async_task::spawn(async { 1 }).await, Ok(1));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::async_task::supervisor::recover_from_panic(info);
+    rustos::test_panic_handler(info)
+}
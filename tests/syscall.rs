@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use rustos::memory;
+use rustos::user::{self, Exit, USER_SPACE_START};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const CODE: u64 = USER_SPACE_START;
+const STACK_END: u64 = USER_SPACE_START + 0x10_0000;
+
+// Exercises every system call and exits with 12, or with the number of the
+// first check that failed.
+global_asm!(
+    ".global payload_start",
+    ".global payload_end",
+    "payload_start:",
+    // write("hello") returns the length.
+    "lea rdi, [rip + 2f]",
+    "mov esi, 5",
+    "mov eax, 0",
+    "syscall",
+    "mov r12, rax",
+    "mov r15, {check_write}",
+    "cmp rax, 5",
+    "jne 1f",
+    // Writing from kernel memory fails with BadAddress.
+    "mov rdi, 0x444444444444",
+    "mov esi, 1",
+    "mov eax, 0",
+    "syscall",
+    "mov r15, {check_bad_address}",
+    "cmp rax, -2",
+    "jne 1f",
+    // Nothing was typed, so read_key would block.
+    "mov eax, 1",
+    "syscall",
+    "mov r15, {check_read_key}",
+    "cmp rax, -3",
+    "jne 1f",
+    // sleep(30) takes at least 30 ms.
+    "mov eax, 4",
+    "syscall",
+    "mov r13, rax",
+    "mov edi, 30",
+    "mov eax, 2",
+    "syscall",
+    "mov eax, 4",
+    "syscall",
+    "sub rax, r13",
+    "mov r15, {check_sleep}",
+    "cmp rax, 30",
+    "jb 1f",
+    // Sizes past the end of user space fail with OutOfMemory.
+    "mov rdi, -1",
+    "mov eax, 5",
+    "syscall",
+    "mov r15, {check_alloc_overflow}",
+    "cmp rax, -4",
+    "jne 1f",
+    // So do sizes over the limit of a single call.
+    "mov rdi, {too_large}",
+    "mov eax, 5",
+    "syscall",
+    "mov r15, {check_alloc_limit}",
+    "cmp rax, -4",
+    "jne 1f",
+    // Allocated memory is zeroed and writable.
+    "mov edi, 100",
+    "mov eax, 5",
+    "syscall",
+    "mov r15, {check_alloc}",
+    "cmp qword ptr [rax], 0",
+    "jne 1f",
+    "mov qword ptr [rax], 7",
+    "mov r14, [rax]",
+    // Unknown calls fail with NoSuchCall.
+    "mov eax, 99",
+    "syscall",
+    "mov r15, {check_unknown}",
+    "cmp rax, -1",
+    "jne 1f",
+    // exit(7 + 5)
+    "lea rdi, [r14 + r12]",
+    "mov eax, 3",
+    "syscall",
+    "1:",
+    "mov rdi, r15",
+    "mov eax, 3",
+    "syscall",
+    "2:",
+    ".ascii \"hello\"",
+    "payload_end:",
+    check_write = const 1,
+    check_bad_address = const 2,
+    check_read_key = const 3,
+    check_sleep = const 4,
+    check_alloc = const 5,
+    check_unknown = const 6,
+    check_alloc_overflow = const 7,
+    check_alloc_limit = const 8,
+    too_large = const rustos::syscall::MAX_ALLOC_SIZE + 1,
+);
+
+extern "C" {
+    static payload_start: u8;
+    static payload_end: u8;
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let (mut mapper, mut frame_allocator) = rustos::test_boot(boot_info);
+
+    let code = Page::containing_address(VirtAddr::new(CODE));
+    let stack = Page::containing_address(VirtAddr::new(STACK_END - 1));
+    user::map_user_pages(
+        code,
+        1,
+        PageTableFlags::WRITABLE,
+        &mut mapper,
+        &mut frame_allocator,
+    )
+    .unwrap();
+    user::map_user_pages(
+        stack,
+        1,
+        PageTableFlags::WRITABLE,
+        &mut mapper,
+        &mut frame_allocator,
+    )
+    .unwrap();
+    memory::set_global(mapper, frame_allocator);
+
+    test_main();
+    rustos::hlt();
+}
+
+#[test_case]
+fn payload() {
+    let exit = unsafe {
+        let start = &payload_start as *const u8;
+        let len = &payload_end as *const u8 as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, CODE as *mut u8, len);
+        user::run(VirtAddr::new(CODE), VirtAddr::new(STACK_END))
+    };
+    assert_eq!(exit, Exit::Exited(12));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::async_task::executor::Executor;
+use rustos::async_task::info::write_task_table;
+use rustos::async_task::sync::oneshot;
+use rustos::async_task::{self, Builder, TaskState};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    serial_print!("task_info::listing...\t");
+
+    let mut executor = Executor::new();
+    executor.spawn(check());
+    executor.run();
+}
+
+async fn check() {
+    let (tx, rx) = oneshot::channel::<()>();
+    let waiter = Builder::new()
+        .name("waiter")
+        .spawn(async move { rx.await.unwrap() });
+    // Let the waiter run until it blocks on the channel.
+    async_task::spawn(async {}).await.unwrap();
+
+    let tasks = async_task::tasks();
+    let info = tasks.iter().find(|t| t.id == waiter.id()).unwrap();
+    assert_eq!(info.name.as_deref(), Some("waiter"));
+    assert_eq!(info.state, TaskState::Waiting);
+    assert_eq!(info.polls, 1);
+    let me = tasks.iter().find(|t| t.state == TaskState::Running);
+    assert!(me.is_some());
+
+    tx.send(()).unwrap();
+    let id = waiter.id();
+    waiter.await.unwrap();
+    let tasks = async_task::tasks();
+    let info = tasks.iter().find(|t| t.id == id).unwrap();
+    assert_eq!(info.state, TaskState::Completed);
+    assert_eq!(info.polls, 2);
+
+    let mut table = alloc::string::String::new();
+    write_task_table(&mut table, &tasks).unwrap();
+    assert!(table.contains("waiter"));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use rustos::thread::{self, spawn_thread};
+use rustos::time;
+
+entry_point!(main);
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::test_boot(boot_info);
+
+    test_main();
+    rustos::hlt();
+}
+
+// Busy loops that never yield still all make progress.
+#[test_case]
+fn preemption() {
+    static COUNTERS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+    let spinners = [0, 1].map(|i| {
+        spawn_thread(move || {
+            while !STOP.load(Ordering::Relaxed) {
+                COUNTERS[i].fetch_add(1, Ordering::Relaxed);
+            }
+            i
+        })
+    });
+    let start = time::ticks();
+    while time::ticks() < start + 10 {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::Relaxed);
+
+    let [first, second] = spinners;
+    assert_eq!(first.join(), 0);
+    assert_eq!(second.join(), 1);
+    assert!(COUNTERS.iter().all(|c| c.load(Ordering::Relaxed) > 0));
+}
+
+#[test_case]
+fn sleep_and_join() {
+    let sleeper = spawn_thread(|| {
+        let start = time::ticks();
+        thread::sleep(50);
+        time::ticks() - start
+    });
+    let yielder = spawn_thread(|| {
+        for _ in 0..10 {
+            thread::yield_now();
+        }
+        thread::current()
+    });
+
+    let yielder_id = yielder.id();
+    assert_eq!(yielder.join(), yielder_id);
+    assert!(sleeper.join() >= time::ms_to_ticks(50));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
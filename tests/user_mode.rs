@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::allocator;
+use rustos::user::{self, Exit, USER_SPACE_START};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const CODE: u64 = USER_SPACE_START;
+const STACK_END: u64 = USER_SPACE_START + 0x10_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let (mut mapper, mut frame_allocator) = rustos::test_boot(boot_info);
+
+    let code = Page::containing_address(VirtAddr::new(CODE));
+    let stack = Page::containing_address(VirtAddr::new(STACK_END - 1));
+    user::map_user_pages(
+        code,
+        1,
+        PageTableFlags::WRITABLE,
+        &mut mapper,
+        &mut frame_allocator,
+    )
+    .unwrap();
+    user::map_user_pages(
+        stack,
+        1,
+        PageTableFlags::WRITABLE,
+        &mut mapper,
+        &mut frame_allocator,
+    )
+    .unwrap();
+
+    run_case(
+        "exit",
+        &[0x48, 0xc7, 0xc0, 0x2a, 0, 0, 0, 0xcd, 0x80],
+        Exit::Exited(42),
+    );
+    // push 7; pop rax; int 0x80
+    run_case("stack", &[0x6a, 0x07, 0x58, 0xcd, 0x80], Exit::Exited(7));
+    run_case("privileged", &[0xf4], Exit::GeneralProtection(0));
+    run_case("invalid_opcode", &[0x0f, 0x0b], Exit::InvalidOpcode);
+    // mov rax, [HEAP_START]; kernel memory is not user-accessible.
+    let mut load = [0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0];
+    load[2..].copy_from_slice(&(allocator::HEAP_START as u64).to_le_bytes());
+    run_case(
+        "kernel_memory",
+        &load,
+        Exit::PageFault {
+            address: allocator::HEAP_START as u64,
+            error: 0b101,
+        },
+    );
+
+    exit_qemu(QemuExitCode::Success);
+    rustos::hlt();
+}
+
+fn run_case(name: &str, code: &[u8], expected: Exit) {
+    serial_print!("user_mode::{}...\t", name);
+    let dst = CODE as *mut u8;
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+        let exit = user::run(VirtAddr::new(CODE), VirtAddr::new(STACK_END));
+        assert_eq!(exit, expected);
+    }
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}